@@ -1,21 +1,27 @@
 #!/bin/bash
+# ./build.sh [init_boot.img] [out.img] [arch]
 
-echo "start build Fuseisk"
-
+IMAGE=${1:-init_boot.img}
+OUT=${2:-new-boot.img}
+ARCH=${3:-x86_64}
 
-#cargo ndk  --platform 30 --target x86_64-linux-android  build  --release
-#cp target/x86_64-linux-android/release/Fuseisk ./
-
-cargo ndk  --platform 30 --target x86_64-linux-android  build
-cp target/x86_64-linux-android/debug/Fuseisk ./
+case "$ARCH" in
+  x86_64) TARGET=x86_64-linux-android ;;
+  x86) TARGET=i686-linux-android ;;
+  arm64) TARGET=aarch64-linux-android ;;
+  arm) TARGET=armv7-linux-androideabi ;;
+  *) echo "unsupported arch $ARCH"; exit 1 ;;
+esac
 
+echo "start build Fuseisk"
 
-magiskboot unpack init_boot.img
+#cargo ndk  --platform 30 --target $TARGET  build  --release
 
-magiskboot cpio  ramdisk.cpio  "extract init  init_back"
+cargo ndk  --platform 30 --target $TARGET  build || exit 1
 
-magiskboot cpio ramdisk.cpio \
-"add 0750 init Fuseisk" \
-"add 0750 init_back init_back" \
+# The host build does the patching, it picks up target/$TARGET/debug/Fuseisk.
+# .cargo/config.toml builds for Android by default, so name the host target.
+HOST=$(rustc -vV | sed -n 's/^host: //p')
+cargo build --target "$HOST" || exit 1
 
-magiskboot repack init_boot.img
\ No newline at end of file
+"./target/$HOST/debug/Fuseisk" patch "$IMAGE" "$OUT" --arch "$ARCH"
@@ -0,0 +1,490 @@
+use sha1::{Digest, Sha1};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::Path;
+use thiserror::Error;
+
+// Android boot image layouts, see system/tools/mkbootimg/include/bootimg/bootimg.h
+//
+// Every image starts with a header, followed by its sections in a fixed order,
+// each padded to the page size. The size of each section is a field in the header.
+//
+//   boot v0-v2:  header | kernel | ramdisk | second | recovery_dtbo (v1+) | dtb (v2)
+//   boot v3-v4:  header | kernel | ramdisk | signature (v4), 4096 bytes pages
+//   vendor_boot: header | vendor ramdisk | dtb | ramdisk table (v4) | bootconfig (v4)
+//
+// init_boot is a boot v4 image without a kernel.
+
+const BOOT_MAGIC: &[u8] = b"ANDROID!";
+const VENDOR_BOOT_MAGIC: &[u8] = b"VNDRBOOT";
+const BOOT_V3_PAGE_SIZE: u32 = 4096;
+
+// Header field offsets
+const HDR_VERSION: usize = 40;
+const V0_PAGE_SIZE: usize = 36;
+const V0_OS_VERSION: usize = 44;
+const V0_NAME: usize = 48;
+const V0_CMDLINE: (usize, usize) = (64, 512);
+const V0_ID: (usize, usize) = (576, 32);
+const V0_EXTRA_CMDLINE: (usize, usize) = (608, 1024);
+const V1_RECOVERY_DTBO_OFFSET: usize = 1636;
+const V1_HEADER_SIZE: usize = 1644;
+const V3_OS_VERSION: usize = 16;
+const V3_HEADER_SIZE: usize = 20;
+const V3_CMDLINE: (usize, usize) = (44, 1536);
+const VENDOR_VERSION: usize = 8;
+const VENDOR_PAGE_SIZE: usize = 12;
+const VENDOR_CMDLINE: (usize, usize) = (28, 2048);
+const VENDOR_NAME: usize = 2080;
+const VENDOR_HEADER_SIZE: usize = 2096;
+const VENDOR_TABLE_ENTRY_NUM: usize = 2116;
+const VENDOR_TABLE_ENTRY_SIZE: usize = 2120;
+
+const VENDOR_RAMDISK_NAME_LEN: usize = 32;
+
+#[derive(Debug, Error)]
+pub enum BootImgError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("not an Android boot image")]
+    BadMagic,
+    #[error("unsupported header version {0}")]
+    UnsupportedVersion(u32),
+    #[error("invalid page size {0}")]
+    BadPageSize(usize),
+    #[error("image truncated in {0}")]
+    Truncated(Section),
+    #[error("vendor ramdisk '{0}' not found")]
+    NoVendorRamdisk(String),
+    #[error("vendor ramdisk '{0}' is out of bounds")]
+    BadVendorRamdisk(String),
+}
+
+pub type BootImgResult<T> = Result<T, BootImgError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Header,
+    Kernel,
+    Ramdisk,
+    Second,
+    // Qualcomm dt.img of pre-v1 images, stored where header_version now is
+    Extra,
+    RecoveryDtbo,
+    Dtb,
+    Signature,
+    VendorRamdiskTable,
+    Bootconfig,
+}
+
+impl Display for Section {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Section::Header => "header",
+            Section::Kernel => "kernel",
+            Section::Ramdisk => "ramdisk",
+            Section::Second => "second",
+            Section::Extra => "extra",
+            Section::RecoveryDtbo => "recovery_dtbo",
+            Section::Dtb => "dtb",
+            Section::Signature => "signature",
+            Section::VendorRamdiskTable => "vendor_ramdisk_table",
+            Section::Bootconfig => "bootconfig",
+        };
+        f.write_str(name)
+    }
+}
+
+// Sections in image order, with the offset of their size field in the header
+fn layout(vendor: bool, version: u32, extra: bool) -> Vec<(Section, usize)> {
+    use Section::*;
+    let mut sections = Vec::new();
+    if vendor {
+        sections.push((Ramdisk, 24));
+        sections.push((Dtb, 2100));
+        if version >= 4 {
+            sections.push((VendorRamdiskTable, 2112));
+            sections.push((Bootconfig, 2124));
+        }
+    } else if version >= 3 {
+        sections.push((Kernel, 8));
+        sections.push((Ramdisk, 12));
+        if version >= 4 {
+            sections.push((Signature, 1580));
+        }
+    } else {
+        sections.push((Kernel, 8));
+        sections.push((Ramdisk, 16));
+        sections.push((Second, 24));
+        if extra {
+            sections.push((Extra, HDR_VERSION));
+        }
+        if version >= 1 {
+            sections.push((RecoveryDtbo, 1632));
+        }
+        if version >= 2 {
+            sections.push((Dtb, 1648));
+        }
+    }
+    sections
+}
+
+// The size of the header struct itself, as written to its header_size field
+fn header_size(vendor: bool, version: u32) -> usize {
+    match (vendor, version) {
+        (true, 3) => 2112,
+        (true, _) => 2128,
+        (false, 0) => 1632,
+        (false, 1) => 1648,
+        (false, 2) => 1660,
+        (false, 3) => 1580,
+        (false, _) => 1584,
+    }
+}
+
+fn read_u32(buf: &[u8], off: usize) -> u32 {
+    buf.get(off..off + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .unwrap_or(0)
+}
+
+fn write_u32(buf: &mut [u8], off: usize, val: u32) {
+    buf[off..off + 4].copy_from_slice(&val.to_le_bytes());
+}
+
+fn read_str(buf: &[u8], (off, len): (usize, usize)) -> String {
+    let field = buf.get(off..off + len).unwrap_or_default();
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+#[inline(always)]
+fn align_to(x: usize, page: usize) -> usize {
+    x.div_ceil(page) * page
+}
+
+// Packed as A(7) B(7) C(7) Y(7) M(4), with the year offset from 2000
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OsVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    pub year: u32,
+    pub month: u32,
+}
+
+impl OsVersion {
+    pub fn from_raw(raw: u32) -> Option<OsVersion> {
+        if raw == 0 {
+            return None;
+        }
+        let ver = raw >> 11;
+        let level = raw & 0x7ff;
+        Some(OsVersion {
+            major: (ver >> 14) & 0x7f,
+            minor: (ver >> 7) & 0x7f,
+            patch: ver & 0x7f,
+            year: (level >> 4) + 2000,
+            month: level & 0xf,
+        })
+    }
+}
+
+impl Display for OsVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{} ({}-{:02})",
+            self.major, self.minor, self.patch, self.year, self.month
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VendorRamdisk {
+    pub name: String,
+    pub ramdisk_type: u32,
+    pub offset: usize,
+    pub size: usize,
+}
+
+pub struct BootImage {
+    vendor: bool,
+    version: u32,
+    page_size: usize,
+    // The raw header, padded to the page size
+    header: Vec<u8>,
+    sections: Vec<(Section, usize, Vec<u8>)>,
+    // Whatever follows the last section (AVB footer, vendor signatures)
+    tail: Vec<u8>,
+}
+
+impl BootImage {
+    pub fn parse(data: &[u8]) -> BootImgResult<BootImage> {
+        let vendor = if data.starts_with(BOOT_MAGIC) {
+            false
+        } else if data.starts_with(VENDOR_BOOT_MAGIC) {
+            true
+        } else {
+            return Err(BootImgError::BadMagic);
+        };
+
+        let mut extra = false;
+        let (version, page_size, hdr_len) = if vendor {
+            let version = read_u32(data, VENDOR_VERSION);
+            if !(3..=4).contains(&version) {
+                return Err(BootImgError::UnsupportedVersion(version));
+            }
+            let page_size = read_u32(data, VENDOR_PAGE_SIZE) as usize;
+            let hdr_size = header_size(true, version);
+            (version, page_size, align_to(hdr_size, page_size.max(1)))
+        } else {
+            let mut version = read_u32(data, HDR_VERSION);
+            if version > 4 {
+                // Before v1 this was the size of the Qualcomm dt.img
+                extra = true;
+                version = 0;
+            }
+            let page_size = if version >= 3 {
+                BOOT_V3_PAGE_SIZE as usize
+            } else {
+                read_u32(data, V0_PAGE_SIZE) as usize
+            };
+            (version, page_size, page_size)
+        };
+        // Before v3 the header fills the first page, so it has to fit in one
+        if !page_size.is_power_of_two() || hdr_len < header_size(vendor, version) {
+            return Err(BootImgError::BadPageSize(page_size));
+        }
+
+        let header = data
+            .get(..hdr_len)
+            .ok_or(BootImgError::Truncated(Section::Header))?
+            .to_vec();
+        let mut pos = hdr_len;
+        let mut sections = Vec::new();
+        for (section, size_off) in layout(vendor, version, extra) {
+            let size = read_u32(&header, size_off) as usize;
+            let body = data
+                .get(pos..)
+                .and_then(|d| d.get(..size))
+                .ok_or(BootImgError::Truncated(section))?;
+            sections.push((section, size_off, body.to_vec()));
+            pos = align_to(pos + size, page_size);
+        }
+        let tail = data.get(pos..).unwrap_or_default().to_vec();
+
+        Ok(BootImage {
+            vendor,
+            version,
+            page_size,
+            header,
+            sections,
+            tail,
+        })
+    }
+
+    pub fn load_from_file(path: &Path) -> BootImgResult<BootImage> {
+        BootImage::parse(&fs::read(path)?)
+    }
+
+    pub fn dump(&self) -> Vec<u8> {
+        let mut header = self.header.clone();
+        let mut pos = header.len();
+        for (section, size_off, body) in &self.sections {
+            write_u32(&mut header, *size_off, body.len() as u32);
+            if *section == Section::RecoveryDtbo {
+                let off = if body.is_empty() { 0 } else { pos as u64 };
+                header[V1_RECOVERY_DTBO_OFFSET..V1_RECOVERY_DTBO_OFFSET + 8]
+                    .copy_from_slice(&off.to_le_bytes());
+            }
+            pos = align_to(pos + body.len(), self.page_size);
+        }
+        if self.vendor {
+            write_u32(
+                &mut header,
+                VENDOR_HEADER_SIZE,
+                header_size(true, self.version) as u32,
+            );
+        } else if self.version >= 3 {
+            write_u32(
+                &mut header,
+                V3_HEADER_SIZE,
+                header_size(false, self.version) as u32,
+            );
+        } else if self.version >= 1 {
+            write_u32(
+                &mut header,
+                V1_HEADER_SIZE,
+                header_size(false, self.version) as u32,
+            );
+        }
+        if !self.vendor && self.version <= 2 {
+            let (off, len) = V0_ID;
+            header[off..off + len].fill(0);
+            header[off..off + 20].copy_from_slice(&self.id());
+        }
+
+        let mut out = header;
+        for (_, _, body) in &self.sections {
+            out.extend_from_slice(body);
+            out.resize(align_to(out.len(), self.page_size), 0);
+        }
+        out.extend_from_slice(&self.tail);
+        out
+    }
+
+    // SHA1 of every section followed by its size, as mkbootimg fills the v0-v2 id
+    fn id(&self) -> [u8; 20] {
+        let mut sha = Sha1::new();
+        for (_, _, body) in &self.sections {
+            sha.update(body);
+            sha.update((body.len() as u32).to_le_bytes());
+        }
+        sha.finalize().into()
+    }
+
+    pub fn dump_to_file(&self, path: &Path) -> BootImgResult<()> {
+        Ok(fs::write(path, self.dump())?)
+    }
+
+    pub fn is_vendor_boot(&self) -> bool {
+        self.vendor
+    }
+
+    pub fn header_version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    pub fn get(&self, section: Section) -> Option<&[u8]> {
+        if section == Section::Header {
+            return Some(&self.header);
+        }
+        self.sections
+            .iter()
+            .find(|(s, _, _)| *s == section)
+            .map(|(_, _, body)| body.as_slice())
+    }
+
+    // Replace the content of a section, false if this image has no such section
+    pub fn set(&mut self, section: Section, data: Vec<u8>) -> bool {
+        match self.sections.iter_mut().find(|(s, _, _)| *s == section) {
+            Some((_, _, body)) => {
+                *body = data;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn kernel(&self) -> &[u8] {
+        self.get(Section::Kernel).unwrap_or_default()
+    }
+
+    pub fn ramdisk(&self) -> &[u8] {
+        self.get(Section::Ramdisk).unwrap_or_default()
+    }
+
+    pub fn second(&self) -> &[u8] {
+        self.get(Section::Second).unwrap_or_default()
+    }
+
+    pub fn dtb(&self) -> &[u8] {
+        self.get(Section::Dtb).unwrap_or_default()
+    }
+
+    pub fn recovery_dtbo(&self) -> &[u8] {
+        self.get(Section::RecoveryDtbo).unwrap_or_default()
+    }
+
+    pub fn set_ramdisk(&mut self, data: Vec<u8>) {
+        self.set(Section::Ramdisk, data);
+    }
+
+    pub fn cmdline(&self) -> String {
+        if self.vendor {
+            read_str(&self.header, VENDOR_CMDLINE)
+        } else if self.version >= 3 {
+            read_str(&self.header, V3_CMDLINE)
+        } else {
+            // mkbootimg spills what does not fit in cmdline into extra_cmdline
+            read_str(&self.header, V0_CMDLINE) + &read_str(&self.header, V0_EXTRA_CMDLINE)
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match (self.vendor, self.version) {
+            (true, _) => read_str(&self.header, (VENDOR_NAME, 16)),
+            (false, 0..=2) => read_str(&self.header, (V0_NAME, 16)),
+            _ => String::new(),
+        }
+    }
+
+    pub fn os_version(&self) -> Option<OsVersion> {
+        let raw = match (self.vendor, self.version) {
+            (true, _) => return None,
+            (false, 0..=2) => read_u32(&self.header, V0_OS_VERSION),
+            _ => read_u32(&self.header, V3_OS_VERSION),
+        };
+        OsVersion::from_raw(raw)
+    }
+
+    // The entries of the v4 vendor ramdisk table
+    pub fn vendor_ramdisks(&self) -> Vec<VendorRamdisk> {
+        let Some(table) = self.get(Section::VendorRamdiskTable) else {
+            return Vec::new();
+        };
+        let num = read_u32(&self.header, VENDOR_TABLE_ENTRY_NUM) as usize;
+        let entry_size = read_u32(&self.header, VENDOR_TABLE_ENTRY_SIZE) as usize;
+        table
+            .chunks(entry_size.max(1))
+            .take(num)
+            .map(|e| VendorRamdisk {
+                size: read_u32(e, 0) as usize,
+                offset: read_u32(e, 4) as usize,
+                ramdisk_type: read_u32(e, 8),
+                name: read_str(e, (12, VENDOR_RAMDISK_NAME_LEN)),
+            })
+            .collect()
+    }
+
+    // Replace one ramdisk of a v4 vendor_boot, shifting the ones after it
+    pub fn set_vendor_ramdisk(&mut self, name: &str, data: Vec<u8>) -> BootImgResult<()> {
+        let entries = self.vendor_ramdisks();
+        let idx = entries
+            .iter()
+            .position(|e| e.name == name)
+            .ok_or_else(|| BootImgError::NoVendorRamdisk(name.to_owned()))?;
+        let entry_size = read_u32(&self.header, VENDOR_TABLE_ENTRY_SIZE) as usize;
+
+        let old = &entries[idx];
+        let mut ramdisk = self.ramdisk().to_vec();
+        let end = old
+            .offset
+            .checked_add(old.size)
+            .filter(|&end| end <= ramdisk.len())
+            .ok_or_else(|| BootImgError::BadVendorRamdisk(name.to_owned()))?;
+        ramdisk.splice(old.offset..end, data.iter().copied());
+        let delta = data.len() as i64 - old.size as i64;
+
+        let mut table = self
+            .get(Section::VendorRamdiskTable)
+            .unwrap_or_default()
+            .to_vec();
+        for (i, e) in entries.iter().enumerate() {
+            let base = i * entry_size;
+            if i == idx {
+                write_u32(&mut table, base, data.len() as u32);
+            } else if e.offset > old.offset {
+                write_u32(&mut table, base + 4, (e.offset as i64 + delta) as u32);
+            }
+        }
+        self.set(Section::Ramdisk, ramdisk);
+        self.set(Section::VendorRamdiskTable, table);
+        Ok(())
+    }
+}
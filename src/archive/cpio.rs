@@ -0,0 +1,424 @@
+use std::ffi::CString;
+use std::fmt::Write as _;
+use std::fs;
+use std::fs::Permissions;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Component, Path};
+use thiserror::Error;
+
+use crate::result::LibcReturn;
+
+// The "new" (SVR4, no CRC) portable format, the only one the kernel unpacks:
+//
+// "070701" followed by 13 fields of 8 hex digits, the NUL terminated name padded
+// to 4 bytes, then the data padded to 4 bytes. The archive ends with an entry
+// named TRAILER!!!, usually followed by zero padding.
+
+const MAGIC: &[u8] = b"070701";
+const HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFIFO: u32 = 0o010000;
+
+#[derive(Debug, Error)]
+pub enum CpioError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid cpio header at offset {0:#x}")]
+    BadHeader(usize),
+    #[error("truncated cpio archive at offset {0:#x}")]
+    Truncated(usize),
+    #[error("entry '{0}' not found")]
+    NotFound(String),
+    #[error("refusing to extract '{0}' outside of the destination")]
+    UnsafePath(String),
+}
+
+pub type CpioResult<T> = Result<T, CpioError>;
+
+#[derive(Debug, Clone, Default)]
+pub struct CpioEntry {
+    pub name: String,
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    pub mtime: u32,
+    pub devmajor: u32,
+    pub devminor: u32,
+    pub rdevmajor: u32,
+    pub rdevminor: u32,
+    pub check: u32,
+    pub data: Vec<u8>,
+    // How the header was written, kept for byte exact round trips: the case of
+    // its hex digits, and the name size when the name is padded with extra NULs
+    // (0 for new or renamed entries)
+    pub upper_hex: bool,
+    pub name_size: u32,
+}
+
+impl CpioEntry {
+    fn is(&self, ty: u32) -> bool {
+        self.mode & S_IFMT == ty
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is(S_IFDIR)
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.is(S_IFREG)
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.is(S_IFLNK)
+    }
+
+    // The name without leading "/" or "./" and trailing "/"
+    pub fn path(&self) -> &str {
+        norm_path(&self.name)
+    }
+}
+
+fn norm_path(path: &str) -> &str {
+    let path = path.trim_start_matches("./").trim_start_matches('/');
+    path.trim_end_matches('/')
+}
+
+// Strictly below `dir`, not `dir` itself
+fn is_under(name: &str, dir: &str) -> bool {
+    name.strip_prefix(dir)
+        .is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[inline(always)]
+fn align4(x: usize) -> usize {
+    (x + 3) & !3
+}
+
+fn parse_hex(buf: &[u8]) -> Option<u32> {
+    u32::from_str_radix(std::str::from_utf8(buf).ok()?, 16).ok()
+}
+
+pub struct Cpio {
+    entries: Vec<CpioEntry>,
+    trailer: CpioEntry,
+    // Everything after the trailer, kept for byte exact round trips
+    tail: Vec<u8>,
+}
+
+impl Default for Cpio {
+    fn default() -> Self {
+        Cpio::new()
+    }
+}
+
+impl Cpio {
+    pub fn new() -> Cpio {
+        Cpio {
+            entries: Vec::new(),
+            trailer: CpioEntry {
+                name: TRAILER.to_owned(),
+                nlink: 1,
+                ..Default::default()
+            },
+            tail: Vec::new(),
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> CpioResult<Cpio> {
+        let mut cpio = Cpio::new();
+        let mut pos = 0;
+        loop {
+            let hdr = data
+                .get(pos..pos + HEADER_LEN)
+                .ok_or(CpioError::Truncated(pos))?;
+            if &hdr[..MAGIC.len()] != MAGIC {
+                return Err(CpioError::BadHeader(pos));
+            }
+            let mut fields = [0_u32; 13];
+            for (i, field) in fields.iter_mut().enumerate() {
+                let start = MAGIC.len() + i * 8;
+                *field = parse_hex(&hdr[start..start + 8]).ok_or(CpioError::BadHeader(pos))?;
+            }
+            let name_start = pos + HEADER_LEN;
+            let name_size = fields[11] as usize;
+            let name = data
+                .get(name_start..name_start + name_size)
+                .ok_or(CpioError::Truncated(name_start))?;
+            let name = name.split(|&b| b == 0).next().unwrap_or(name);
+            let name = std::str::from_utf8(name).map_err(|_| CpioError::BadHeader(pos))?;
+
+            let data_start = align4(name_start + name_size);
+            let data_end = data_start + fields[6] as usize;
+            let body = data
+                .get(data_start..data_end)
+                .ok_or(CpioError::Truncated(data_start))?;
+
+            let entry = CpioEntry {
+                name: name.to_owned(),
+                ino: fields[0],
+                mode: fields[1],
+                uid: fields[2],
+                gid: fields[3],
+                nlink: fields[4],
+                mtime: fields[5],
+                devmajor: fields[7],
+                devminor: fields[8],
+                rdevmajor: fields[9],
+                rdevminor: fields[10],
+                check: fields[12],
+                data: body.to_vec(),
+                upper_hex: hdr[MAGIC.len()..].iter().any(u8::is_ascii_uppercase),
+                name_size: fields[11],
+            };
+
+            pos = align4(data_end).min(data.len());
+            if entry.name == TRAILER {
+                cpio.trailer = entry;
+                cpio.tail = data[pos..].to_vec();
+                return Ok(cpio);
+            }
+            cpio.entries.push(entry);
+        }
+    }
+
+    pub fn load_from_file(path: &Path) -> CpioResult<Cpio> {
+        Cpio::parse(&fs::read(path)?)
+    }
+
+    fn dump_entry(out: &mut Vec<u8>, e: &CpioEntry) {
+        let name_size = (e.name.len() as u32 + 1).max(e.name_size);
+        let fields = [
+            e.ino,
+            e.mode,
+            e.uid,
+            e.gid,
+            e.nlink,
+            e.mtime,
+            e.data.len() as u32,
+            e.devmajor,
+            e.devminor,
+            e.rdevmajor,
+            e.rdevminor,
+            name_size,
+            e.check,
+        ];
+        let mut hdr = String::with_capacity(HEADER_LEN);
+        for field in fields {
+            if e.upper_hex {
+                write!(hdr, "{:08X}", field).ok();
+            } else {
+                write!(hdr, "{:08x}", field).ok();
+            }
+        }
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(hdr.as_bytes());
+        let name_end = out.len() + name_size as usize;
+        out.extend_from_slice(e.name.as_bytes());
+        out.resize(name_end, 0);
+        out.resize(align4(out.len()), 0);
+        out.extend_from_slice(&e.data);
+        out.resize(align4(out.len()), 0);
+    }
+
+    pub fn dump(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for e in &self.entries {
+            Cpio::dump_entry(&mut out, e);
+        }
+        Cpio::dump_entry(&mut out, &self.trailer);
+        out.extend_from_slice(&self.tail);
+        out
+    }
+
+    pub fn dump_to_file(&self, path: &Path) -> CpioResult<()> {
+        Ok(fs::write(path, self.dump())?)
+    }
+
+    pub fn entries(&self) -> &[CpioEntry] {
+        &self.entries
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        self.find(path).is_some()
+    }
+
+    pub fn find(&self, path: &str) -> Option<&CpioEntry> {
+        let path = norm_path(path);
+        self.entries.iter().find(|e| e.path() == path)
+    }
+
+    // Entries under `path`, only the direct children unless recursive
+    pub fn ls(&self, path: &str, recursive: bool) -> Vec<&CpioEntry> {
+        let dir = norm_path(path);
+        self.entries
+            .iter()
+            .filter(|e| {
+                let name = e.path();
+                let rest = if dir.is_empty() {
+                    name
+                } else {
+                    match name.strip_prefix(dir).and_then(|s| s.strip_prefix('/')) {
+                        Some(rest) => rest,
+                        None => return false,
+                    }
+                };
+                !rest.is_empty() && (recursive || !rest.contains('/'))
+            })
+            .collect()
+    }
+
+    fn next_ino(&self) -> u32 {
+        self.entries.iter().map(|e| e.ino).max().unwrap_or(0) + 1
+    }
+
+    // Insert or replace the entry at `path`
+    fn insert(&mut self, path: &str, mode: u32, data: Vec<u8>) {
+        let name = norm_path(path);
+        if let Some(e) = self.entries.iter_mut().find(|e| e.path() == name) {
+            e.mode = mode;
+            e.data = data;
+            return;
+        }
+        let entry = CpioEntry {
+            name: name.to_owned(),
+            ino: self.next_ino(),
+            mode,
+            nlink: if mode & S_IFMT == S_IFDIR { 2 } else { 1 },
+            data,
+            // New entries follow the rest of the archive
+            upper_hex: self.trailer.upper_hex,
+            ..Default::default()
+        };
+        self.entries.push(entry);
+    }
+
+    pub fn add(&mut self, mode: u32, path: &str, data: Vec<u8>) {
+        self.insert(path, S_IFREG | (mode & 0o7777), data);
+    }
+
+    pub fn add_file(&mut self, mode: u32, path: &str, file: &Path) -> CpioResult<()> {
+        let data = fs::read(file)?;
+        self.add(mode, path, data);
+        Ok(())
+    }
+
+    pub fn mkdir(&mut self, mode: u32, path: &str) {
+        self.insert(path, S_IFDIR | (mode & 0o7777), Vec::new());
+    }
+
+    pub fn ln(&mut self, target: &str, path: &str) {
+        self.insert(path, S_IFLNK | 0o777, target.as_bytes().to_vec());
+    }
+
+    pub fn rm(&mut self, path: &str, recursive: bool) -> CpioResult<()> {
+        let path = norm_path(path);
+        let len = self.entries.len();
+        self.entries.retain(|e| {
+            let name = e.path();
+            name != path && !(recursive && is_under(name, path))
+        });
+        if self.entries.len() == len {
+            return Err(CpioError::NotFound(path.to_owned()));
+        }
+        Ok(())
+    }
+
+    // Rename `from` to `to`, along with everything under it
+    pub fn mv(&mut self, from: &str, to: &str) -> CpioResult<()> {
+        let from = norm_path(from);
+        let to = norm_path(to);
+        if !self.exists(from) {
+            return Err(CpioError::NotFound(from.to_owned()));
+        }
+        if from == to {
+            return Ok(());
+        }
+        // Whatever was at the destination gets replaced, including its children
+        self.entries
+            .retain(|e| e.path() != to && !is_under(e.path(), to));
+        for e in &mut self.entries {
+            let name = e.path();
+            if name == from {
+                e.name = to.to_owned();
+                e.name_size = 0;
+            } else if is_under(name, from) {
+                e.name = format!("{}{}", to, &name[from.len()..]);
+                e.name_size = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn extract_entry(e: &CpioEntry, dest: &Path) -> CpioResult<()> {
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let perm = Permissions::from_mode(e.mode & 0o7777);
+        if e.is_dir() {
+            fs::create_dir_all(dest)?;
+            fs::set_permissions(dest, perm)?;
+            return Ok(());
+        }
+        if dest.symlink_metadata().is_ok() {
+            fs::remove_file(dest)?;
+        }
+        match e.mode & S_IFMT {
+            S_IFREG => {
+                fs::write(dest, &e.data)?;
+                fs::set_permissions(dest, perm)?;
+            }
+            S_IFLNK => symlink(Path::new(std::ffi::OsStr::from_bytes(&e.data)), dest)?,
+            S_IFCHR | S_IFBLK | S_IFIFO => {
+                let path = CString::new(dest.as_os_str().as_bytes())
+                    .map_err(|_| CpioError::UnsafePath(e.name.clone()))?;
+                let dev = libc::makedev(e.rdevmajor, e.rdevminor);
+                unsafe { libc::mknod(path.as_ptr(), e.mode as libc::mode_t, dev) }
+                    .check_io_err()?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn dest_path(dir: &Path, e: &CpioEntry) -> CpioResult<std::path::PathBuf> {
+        let name = Path::new(e.path());
+        if name
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(CpioError::UnsafePath(e.name.clone()));
+        }
+        Ok(dir.join(name))
+    }
+
+    // Extract a single entry to `out`
+    pub fn extract(&self, path: &str, out: &Path) -> CpioResult<()> {
+        let e = self
+            .find(path)
+            .ok_or_else(|| CpioError::NotFound(norm_path(path).to_owned()))?;
+        Cpio::extract_entry(e, out)
+    }
+
+    // Extract every entry into the directory `dir`
+    pub fn extract_all(&self, dir: &Path) -> CpioResult<()> {
+        fs::create_dir_all(dir)?;
+        for e in &self.entries {
+            if e.path().is_empty() {
+                continue;
+            }
+            Cpio::extract_entry(e, &Cpio::dest_path(dir, e)?)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,2 @@
+pub mod bootimg;
+pub mod cpio;
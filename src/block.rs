@@ -0,0 +1,157 @@
+use std::fs;
+use std::path::PathBuf;
+use libc::{makedev, mknod, usleep, S_IFBLK};
+use Fuseisk::cstr::Utf8CStr;
+use Fuseisk::result::{LibcReturn, LoggedError, LoggedResult, ResultExt};
+use Fuseisk::{debug, info};
+use crate::bootconfig::{BootConfig, KeyValue};
+
+// Block devices before ueventd runs, found through sysfs.
+//
+// Every /sys/dev/block/<major>:<minor>/uevent has MAJOR, MINOR and DEVNAME,
+// partitions also have PARTNAME. Nodes are created in a directory of our own,
+// ueventd populates /dev/block later on.
+
+const SYS_DEV_BLOCK: &str = "/sys/dev/block";
+pub const BLOCK_DIR: &str = "/dev/fuseisk/block";
+// Holds BLOCK_DIR, removed once the nodes are mounted
+pub const DEV_DIR: &str = "/dev/fuseisk";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockDevice {
+    pub major: u32,
+    pub minor: u32,
+    pub devname: String,
+    pub partname: Option<String>,
+}
+
+impl BlockDevice {
+    fn parse(uevent: &str) -> Option<BlockDevice> {
+        let (mut major, mut minor, mut devname, mut partname) = (None, None, "", None);
+        for line in uevent.lines() {
+            match line.split_once('=') {
+                Some(("MAJOR", v)) => major = v.parse::<u32>().ok(),
+                Some(("MINOR", v)) => minor = v.parse::<u32>().ok(),
+                Some(("DEVNAME", v)) => devname = v,
+                Some(("PARTNAME", v)) => partname = Some(v.to_owned()),
+                _ => {}
+            }
+        }
+        Some(BlockDevice {
+            major: major?,
+            minor: minor?,
+            devname: devname.to_owned(),
+            partname,
+        })
+    }
+}
+
+pub struct BlockDevices {
+    sys_dir: PathBuf,
+    node_dir: String,
+    slot: String,
+    // Names for raw devices without PARTNAME, from androidboot.partition_map
+    partition_map: Vec<KeyValue>,
+    devices: Vec<BlockDevice>,
+}
+
+impl BlockDevices {
+    pub fn new(config: &BootConfig) -> BlockDevices {
+        BlockDevices::with_dirs(SYS_DEV_BLOCK, BLOCK_DIR, config)
+    }
+
+    pub fn with_dirs(sys_dir: &str, node_dir: &str, config: &BootConfig) -> BlockDevices {
+        BlockDevices {
+            sys_dir: PathBuf::from(sys_dir),
+            node_dir: node_dir.to_owned(),
+            slot: config.slot.clone(),
+            partition_map: config.partition_map.clone(),
+            devices: Vec::new(),
+        }
+    }
+
+    pub fn scan(&mut self) {
+        self.devices = fs::read_dir(&self.sys_dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|e| fs::read_to_string(e.path().join("uevent")).ok())
+            .filter_map(|uevent| BlockDevice::parse(&uevent))
+            .collect();
+    }
+
+    #[cfg(test)]
+    pub fn devices(&self) -> &[BlockDevice] {
+        &self.devices
+    }
+
+    fn partname<'a>(&'a self, dev: &'a BlockDevice) -> Option<&'a str> {
+        dev.partname.as_deref().or_else(|| {
+            self.partition_map
+                .iter()
+                .find(|kv| kv.key == dev.devname)
+                .map(|kv| kv.value.as_str())
+        })
+    }
+
+    fn find_exact(&self, name: &str) -> Option<&BlockDevice> {
+        self.devices
+            .iter()
+            .find(|dev| self.partname(dev).is_some_and(|p| p.eq_ignore_ascii_case(name)))
+    }
+
+    // The partition of the current slot first, then the name as is
+    pub fn find(&self, name: &str) -> Option<&BlockDevice> {
+        if !self.slot.is_empty() {
+            if let Some(dev) = self.find_exact(&format!("{}{}", name, self.slot)) {
+                return Some(dev);
+            }
+        }
+        self.find_exact(name)
+    }
+
+    // The device might not be ready yet, scan up to 3 times 10ms apart
+    fn wait_for(&mut self, name: &str) -> Option<BlockDevice> {
+        for _ in 0..3 {
+            self.scan();
+            if let Some(dev) = self.find(name) {
+                return Some(dev.clone());
+            }
+            unsafe { usleep(10000) };
+        }
+        None
+    }
+
+    fn make_node(name: &str, dev: &BlockDevice, path: &Utf8CStr) -> bool {
+        debug!("Setup {}: [{}] ({}, {})", name, path, dev.major, dev.minor);
+        path.remove().ok();
+        unsafe { mknod(path.as_ptr(), S_IFBLK | 0o600, makedev(dev.major, dev.minor)) }
+            .check_os_err("mknod", Some(path), None)
+            .log()
+            .is_ok()
+    }
+
+    // Create the node of partition `name` at `path`
+    pub fn setup_at(&mut self, name: &str, path: &Utf8CStr) -> bool {
+        match self.wait_for(name) {
+            Some(dev) => BlockDevices::make_node(name, &dev, path),
+            None => false,
+        }
+    }
+
+    // Create the node of partition `name` in our block directory, returning its path
+    pub fn setup(&mut self, name: &str) -> LoggedResult<String> {
+        let Some(dev) = self.wait_for(name) else {
+            info!("Cannot find block device for {}", name);
+            return Err(LoggedError::default());
+        };
+        let mut dir = self.node_dir.clone();
+        Utf8CStr::from_string(&mut dir).mkdirs(0o755)?;
+        let mut path = format!("{}/{}", self.node_dir, self.partname(&dev).unwrap_or(name));
+        if BlockDevices::make_node(name, &dev, Utf8CStr::from_string(&mut path)) {
+            Ok(path)
+        } else {
+            Err(LoggedError::default())
+        }
+    }
+}
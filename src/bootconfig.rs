@@ -1,37 +1,194 @@
-use std::{ffi::c_char, fs};
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::{ffi::c_char, fs, io, mem};
+use thiserror::Error;
+use Fuseisk::result::ResultExt;
 use Fuseisk::{debug, info};
 
+// Every bootconfig value is an array, a single element one is a scalar
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Scalar(String),
+    List(Vec<String>),
+}
+
+impl Value {
+    fn from_items(mut items: Vec<String>) -> Value {
+        if items.len() == 1 {
+            Value::Scalar(items.pop().unwrap())
+        } else {
+            Value::List(items)
+        }
+    }
+
+    pub fn items(&self) -> &[String] {
+        match self {
+            Value::Scalar(s) => std::slice::from_ref(s),
+            Value::List(v) => v,
+        }
+    }
+
+    fn append(&mut self, items: Vec<String>) {
+        let mut all = self.items().to_vec();
+        all.extend(items);
+        *self = Value::from_items(all);
+    }
+}
+
+// Lists print comma separated, the way they are written on the cmdline
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.items().join(","))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct KeyValue {
-    key: String,
-    value: String,
+    pub(crate) key: String,
+    pub(crate) value: String,
 }
+#[derive(Default)]
 pub struct BootConfig {
     pub(crate) skip_initramfs: bool,
     pub(crate) force_normal_boot: bool,
     pub(crate) rootwait: bool,
     pub(crate) emulator: bool,
+    pub(crate) mode: String,
     pub(crate) slot: String,
     pub(crate) dt_dir: String,
     pub(crate) fstab_suffix: String,
     pub(crate) hardware: String,
     pub(crate) hardware_plat: String,
+    pub(crate) boot_devices: Vec<String>,
     pub(crate) partition_map: Vec<KeyValue>,
+    pub(crate) init_args: Vec<String>,
+    // The androidboot.* keys already set, see BootConfig::set
+    pub(crate) set_keys: Vec<&'static str>,
 }
 
 const DEFAULT_DT_DIR: &str = "/proc/device-tree/firmware/android";
+const CMDLINE_PATH: &str = "/proc/cmdline";
+// Only exists on 5.10+ kernels
+const BOOTCONFIG_PATH: &str = "/proc/bootconfig";
+
+// The keys BootConfig::set picks up, matched with parameq
+const CONFIG_KEYS: &[&str] = &[
+    "androidboot.boot_devices",
+    "androidboot.slot_suffix",
+    "androidboot.mode",
+    "androidboot.slot",
+    "skip_initramfs",
+    "androidboot.force_normal_boot",
+    "rootwait",
+    "androidboot.android_dt_dir",
+    "androidboot.hardware",
+    "androidboot.hardware.platform",
+    "androidboot.fstab_suffix",
+    "qemu",
+    "androidboot.partition_map",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    Cmdline,
+    Bootconfig,
+    DeviceTree,
+}
+
+impl Display for ConfigSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConfigSource::Cmdline => "cmdline",
+            ConfigSource::Bootconfig => "bootconfig",
+            ConfigSource::DeviceTree => "device tree",
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("cannot read {0} from {}: {2}", .1.display())]
+pub struct ConfigError(pub ConfigSource, pub PathBuf, #[source] pub io::Error);
+
+pub type ConfigResult<T> = Result<T, ConfigError>;
+
 impl BootConfig {
-    pub fn init(&mut self) -> () {
-        self.set(parse_cmdline(&fs::read_to_string("/proc/cmdline").unwrap()));
-        self.set(parse_bootconfig(
-            &fs::read_to_string("/proc/bootconfig").unwrap(),
-        ));
-        debug!("Device config:\n");
-        info!("Device config:\n");
+    pub fn init(&mut self) {
+        // A missing source is not fatal, boot with whatever we have
+        for result in self.load(Path::new(CMDLINE_PATH), Path::new(BOOTCONFIG_PATH)) {
+            result.log_ok();
+        }
+        info!("Device config:");
         self.print();
     }
-    pub fn set(&mut self, mut kv: Vec<(String, String)>) {
+
+    // Load all sources, returning whether each of cmdline, bootconfig and
+    // device tree could be read
+    pub fn load(&mut self, cmdline: &Path, bootconfig: &Path) -> [ConfigResult<()>; 3] {
+        let mut cmdline = read_cmdline(cmdline);
+        let mut bootconfig = read_bootconfig(bootconfig);
+        let c = cmdline.as_mut().map(mem::take).unwrap_or_default();
+        let b = bootconfig.as_mut().map(mem::take).unwrap_or_default();
+        self.find_dt_dir(&c, &b);
+        let mut dt = parse_dt(&self.dt_dir)
+            .map_err(|e| ConfigError(ConfigSource::DeviceTree, PathBuf::from(&self.dt_dir), e));
+        self.apply(dt.as_mut().map(mem::take).unwrap_or_default(), c, b);
+        [cmdline.map(drop), bootconfig.map(drop), dt.map(drop)]
+    }
+
+    // The device tree is read first, but where it is comes from the other two
+    pub fn find_dt_dir(&mut self, cmdline: &Cmdline, bootconfig: &[(String, Value)]) {
+        if !self.dt_dir.is_empty() {
+            return;
+        }
+        self.dt_dir = cmdline
+            .params
+            .iter()
+            .chain(bootconfig)
+            .find(|(k, _)| parameq(k, "androidboot.android_dt_dir"))
+            .map(|(_, v)| v.to_string())
+            .unwrap_or_else(|| DEFAULT_DT_DIR.to_owned());
+    }
+
+    // In the order of init's PropertyInitialize(): device tree, cmdline, bootconfig
+    pub fn apply(
+        &mut self,
+        dt: Vec<(String, Value)>,
+        cmdline: Cmdline,
+        bootconfig: Vec<(String, Value)>,
+    ) {
+        self.set(dt);
+        self.init_args = cmdline.init_args;
+        self.set(cmdline.params);
+        self.set(bootconfig);
+    }
+
+    pub fn set(&mut self, kv: Vec<(String, Value)>) {
         for (key, value) in kv {
-            match key.as_str() {
+            let Some(&key) = CONFIG_KEYS.iter().find(|k| parameq(k, &key)) else {
+                continue;
+            };
+            // init turns these into ro.boot.* properties, which keep their first value
+            if key.starts_with("androidboot.") {
+                if self.set_keys.contains(&key) {
+                    debug!("Skip [{}], already set", key);
+                    continue;
+                }
+                self.set_keys.push(key);
+            }
+            // Lists only matter for a few keys, the rest take the joined form
+            if key == "androidboot.boot_devices" {
+                // A list in bootconfig, comma separated on the cmdline
+                self.boot_devices = value
+                    .items()
+                    .iter()
+                    .flat_map(|v| v.split(','))
+                    .map(|v| v.trim().to_owned())
+                    .filter(|v| !v.is_empty())
+                    .collect();
+                continue;
+            }
+            let value = value.to_string();
+            match key {
                 "androidboot.slot_suffix" => {
                     // Many Amlogic devices are A-only but have slot_suffix...
                     if value == "normal" {
@@ -40,6 +197,9 @@ impl BootConfig {
                     }
                     self.slot = value;
                 }
+                "androidboot.mode" => {
+                    self.mode = value;
+                }
                 "androidboot.slot" => {
                     let s_ = String::from("_");
                     self.slot = s_ + &value;
@@ -70,11 +230,16 @@ impl BootConfig {
                 "qemu" => {
                     self.emulator = true;
                 }
-                // "androidboot.partition_map" => {
-                //     for (k, v) in parse_partition_map(value) {
-                //         self.partition_map.push((k, v));
-                //     }
-                // }
+                // Names raw block devices, e.g. `vdb,metadata;vdc,userdata`
+                // maps vdb to metadata and vdc to userdata, see init/devices.cpp.
+                // Lookups there stop at the first match, so later duplicates are dead
+                "androidboot.partition_map" => {
+                    for (key, value) in parse_partition_map(&value) {
+                        if self.partition_map.iter().all(|kv| kv.key != key) {
+                            self.partition_map.push(KeyValue { key, value });
+                        }
+                    }
+                }
                 _ => {}
             }
         }
@@ -85,53 +250,409 @@ impl BootConfig {
         info!("skip_initramfs=[{}]", self.skip_initramfs);
         debug!("force_normal_boot=[{}]", self.force_normal_boot);
         debug!("rootwait=[{}]", self.rootwait);
+        debug!("mode=[{}]", self.mode);
         debug!("slot=[{}]", self.slot);
         debug!("dt_dir=[{}]", self.dt_dir);
         debug!("fstab_suffix=[{}]", self.fstab_suffix);
         debug!("hardware=[{}]", self.hardware);
         debug!("hardware.platform=[{}]", self.hardware_plat);
         debug!("emulator=[{}]", self.emulator);
-        // debug!("partition_map=[{:?}]", self.partition_map);
+        debug!("boot_devices=[{}]", self.boot_devices.join(", "));
+        let map: Vec<String> = self
+            .partition_map
+            .iter()
+            .map(|kv| format!("{}={}", kv.key, kv.value))
+            .collect();
+        debug!("partition_map=[{}]", map.join(", "));
+        debug!("init_args=[{}]", self.init_args.join(" "));
     }
 }
 
-pub fn parse_cmdline(input: &str) -> Vec<(String, String)> {
-    input
-        .split_whitespace() // 使用空白字符分割每一项
-        .map(|token| {
-            if let Some(idx) = token.find('=') {
-                let (key, value) = token.split_at(idx);
-                (key.to_string(), value[1..].to_string()) // 跳过 '='
-            } else {
-                (token.to_string(), String::new())
-            }
-        })
-        .collect()
+// Android properties passed through the device tree, one file per property,
+// read the same way as ProcessKernelDt() in init/property_service.cpp
+pub fn parse_dt(dir: &str) -> io::Result<Vec<(String, Value)>> {
+    let read = |name: &str| {
+        fs::read(Path::new(dir).join(name))
+            .map(|data| String::from_utf8_lossy(&data).trim_end_matches('\0').to_owned())
+    };
+    // Most devices don't pass anything through the device tree
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    if read("compatible").ok().as_deref() != Some("android,firmware") {
+        return Ok(Vec::new());
+    }
+    let mut names: Vec<String> = entries
+        .flatten()
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_file()))
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter(|name| name != "compatible" && name != "name")
+        .collect();
+    names.sort();
+
+    let mut kv = Vec::new();
+    for name in names {
+        kv.push((
+            format!("androidboot.{}", name),
+            Value::Scalar(read(&name)?.replace(',', ".")),
+        ));
+    }
+    Ok(kv)
+}
+
+// `<device>,<partition>` pairs separated by `;`
+pub fn parse_partition_map(input: &str) -> Vec<(String, String)> {
+    let mut result = Vec::new();
+    for map in input.split(';').filter(|m| !m.is_empty()) {
+        match map.split(',').collect::<Vec<_>>()[..] {
+            [device, partition] => result.push((device.to_owned(), partition.to_owned())),
+            _ => info!("Expected a comma separated device,partition mapping, but found {}", map),
+        }
+    }
+    result
+}
+
+// Kernel module parameter names treat `-` and `_` as the same character
+pub fn parameq(a: &str, b: &str) -> bool {
+    let norm = |c: u8| if c == b'-' { b'_' } else { c };
+    a.len() == b.len() && a.bytes().zip(b.bytes()).all(|(x, y)| norm(x) == norm(y))
+}
+
+#[derive(Default)]
+pub struct Cmdline {
+    pub params: Vec<(String, Value)>,
+    // Everything after `--`, the kernel passes these on to init
+    pub init_args: Vec<String>,
+}
+
+// One `param[=value]` following next_arg() in the kernel's lib/cmdline.c:
+// double quotes group spaces, and are dropped around the whole argument or
+// around the value.
+fn next_arg(args: &str) -> (String, Option<String>, &str) {
+    let mut arg = args;
+    let mut in_quote = false;
+    let mut quoted = false;
+    if let Some(rest) = arg.strip_prefix('"') {
+        arg = rest;
+        in_quote = true;
+        quoted = true;
+    }
+
+    let mut end = arg.len();
+    let mut equals = 0;
+    for (i, c) in arg.char_indices() {
+        if c.is_ascii_whitespace() && !in_quote {
+            end = i;
+            break;
+        }
+        if equals == 0 && c == '=' {
+            equals = i;
+        }
+        if c == '"' {
+            in_quote = !in_quote;
+        }
+    }
+    let rest = arg[end..].trim_start_matches(|c: char| c.is_ascii_whitespace());
+
+    let token = &arg[..end];
+    let (mut param, mut value) = if equals == 0 {
+        (token, None)
+    } else {
+        (&token[..equals], Some(&token[equals + 1..]))
+    };
+    // Don't include quotes in the value
+    let mut trimmed = false;
+    if let Some(v) = value.and_then(|v| v.strip_prefix('"')) {
+        trimmed = v.ends_with('"');
+        value = Some(v.strip_suffix('"').unwrap_or(v));
+    }
+    // The closing quote of an argument that started with one
+    if quoted && !trimmed && token.ends_with('"') {
+        match value.as_mut() {
+            Some(v) => *v = v.strip_suffix('"').unwrap_or(v),
+            None => param = param.strip_suffix('"').unwrap_or(param),
+        }
+    }
+    (param.to_owned(), value.map(str::to_owned), rest)
+}
+
+pub fn read_cmdline(path: &Path) -> ConfigResult<Cmdline> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| ConfigError(ConfigSource::Cmdline, path.to_owned(), e))?;
+    Ok(parse_cmdline(&content))
 }
 
-/// 解析 key=value 格式的字符串，支持引号包裹的内容
-pub fn parse_bootconfig(input: &str) -> Vec<(String, String)> {
-    input
-        .split("\n") // 使用空白字符分割每一项
-        .map(|token| {
-            if let Some(idx) = token.find('=') {
-                let (key_part, value_part) = token.split_at(idx);
+pub fn parse_cmdline(input: &str) -> Cmdline {
+    let mut cmdline = Cmdline {
+        params: Vec::new(),
+        init_args: Vec::new(),
+    };
+    let mut args = input.trim_start_matches(|c: char| c.is_ascii_whitespace());
+    let mut after_dashes = false;
+    while !args.is_empty() {
+        let (param, value, rest) = next_arg(args);
+        args = rest;
+        if after_dashes {
+            cmdline.init_args.push(match value {
+                Some(v) => format!("{}={}", param, v),
+                None => param,
+            });
+        } else if value.is_none() && param == "--" {
+            after_dashes = true;
+        } else {
+            cmdline
+                .params
+                .push((param, Value::Scalar(value.unwrap_or_default())));
+        }
+    }
+    cmdline
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    // `=`
+    Set,
+    // `+=`
+    Append,
+    // `:=`
+    Override,
+}
+
+// The kernel bootconfig grammar, see Documentation/admin-guide/bootconfig.rst
+//
+//   key.word = value              # comment
+//   key { sub = "a", "b"; other }
+//   key += value                  # append to the array
+//   key := value                  # override the previous value
+//
+// Values are comma separated arrays, quoted with " or ' to contain delimiters,
+// and arrays may continue on the next line after a comma. Inside quotes a
+// backslash escapes a quote or itself, outside a trailing backslash continues
+// the line.
+struct BootconfigParser {
+    chars: Vec<char>,
+    pos: usize,
+    out: Vec<(String, Value)>,
+}
+
+impl BootconfigParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
 
-                // 提取 key 并去除前后空白
-                let key = key_part.trim().to_string();
+    fn peek_at(&self, off: usize) -> Option<char> {
+        self.chars.get(self.pos + off).copied()
+    }
 
-                // 提取 value（跳过 '=' 后）
-                let mut value = value_part[1..].trim().to_string();
+    // Spaces and tabs, including line continuations
+    fn skip_space(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t' | '\r') => self.pos += 1,
+                Some('\\') if self.peek_at(1) == Some('\n') => self.pos += 2,
+                _ => break,
+            }
+        }
+    }
 
-                // 去除两边的双引号（如果存在）
-                if value.starts_with('"') && value.ends_with('"') && value.len() >= 2 {
-                    value = value[1..value.len() - 1].to_string();
+    fn skip_comment(&mut self) {
+        while self.peek().is_some_and(|c| c != '\n') {
+            self.pos += 1;
+        }
+    }
+
+    // Everything that may separate two statements or two array elements
+    fn skip_blank(&mut self, semicolon: bool) {
+        loop {
+            self.skip_space();
+            match self.peek() {
+                Some('\n') => self.pos += 1,
+                Some(';') if semicolon => self.pos += 1,
+                Some('#') => self.skip_comment(),
+                _ => break,
+            }
+        }
+    }
+
+    fn skip_line(&mut self) {
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            if c == '\n' || c == ';' {
+                break;
+            }
+        }
+    }
+
+    fn read_key(&mut self) -> String {
+        let mut key = String::new();
+        while let Some(c) = self
+            .peek()
+            .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            key.push(c);
+            self.pos += 1;
+        }
+        key
+    }
+
+    fn read_value(&mut self) -> Option<String> {
+        let mut value = String::new();
+        match self.peek() {
+            Some(quote @ ('"' | '\'')) => {
+                self.pos += 1;
+                loop {
+                    match self.peek()? {
+                        c if c == quote => break,
+                        '\\' if matches!(self.peek_at(1), Some('"' | '\'' | '\\')) => {
+                            value.push(self.peek_at(1)?);
+                            self.pos += 1;
+                        }
+                        c => value.push(c),
+                    }
+                    self.pos += 1;
+                }
+                self.pos += 1;
+            }
+            _ => {
+                while let Some(c) = self.peek() {
+                    if matches!(c, ',' | ';' | '\n' | '#' | '}') {
+                        break;
+                    }
+                    if c == '\\' && self.peek_at(1) == Some('\n') {
+                        self.pos += 2;
+                        continue;
+                    }
+                    value.push(c);
+                    self.pos += 1;
                 }
+                value.truncate(value.trim_end().len());
+            }
+        }
+        Some(value)
+    }
+
+    fn read_values(&mut self) -> Option<Vec<String>> {
+        let mut values = Vec::new();
+        loop {
+            self.skip_space();
+            values.push(self.read_value()?);
+            self.skip_space();
+            if self.peek() != Some(',') {
+                break;
+            }
+            self.pos += 1;
+            self.skip_blank(false);
+        }
+        // The value has to end the statement
+        match self.peek() {
+            None | Some('\n' | ';' | '#' | '}') => Some(values),
+            _ => None,
+        }
+    }
+
+    fn assign(&mut self, key: String, op: Op, items: Vec<String>) {
+        let existing = self.out.iter_mut().find(|(k, _)| *k == key);
+        match (existing, op) {
+            (Some((_, value)), Op::Append) => value.append(items),
+            (Some((_, value)), op) => {
+                if matches!(op, Op::Set) {
+                    info!("bootconfig: [{}] redefined", key);
+                }
+                *value = Value::from_items(items);
+            }
+            (None, _) => self.out.push((key, Value::from_items(items))),
+        }
+    }
+
+    fn parse_block(&mut self, prefix: &str, nested: bool) {
+        loop {
+            self.skip_blank(true);
+            match self.peek() {
+                None => {
+                    if nested {
+                        info!("bootconfig: unclosed block [{}]", prefix);
+                    }
+                    return;
+                }
+                Some('}') => {
+                    self.pos += 1;
+                    if nested {
+                        return;
+                    }
+                    info!("bootconfig: unexpected }}");
+                    continue;
+                }
+                _ => {}
+            }
 
-                (key, value)
+            let start = self.pos;
+            let word = self.read_key();
+            if word.is_empty() || word.starts_with('.') || word.ends_with('.') {
+                info!("bootconfig: invalid key at offset {}", start);
+                self.skip_line();
+                continue;
+            }
+            let key = if prefix.is_empty() {
+                word
             } else {
-                (token.to_string(), String::new())
+                format!("{}.{}", prefix, word)
+            };
+
+            self.skip_space();
+            let op = match (self.peek(), self.peek_at(1)) {
+                (Some('{'), _) => {
+                    self.pos += 1;
+                    self.parse_block(&key, true);
+                    continue;
+                }
+                (Some('='), _) => {
+                    self.pos += 1;
+                    Op::Set
+                }
+                (Some('+'), Some('=')) => {
+                    self.pos += 2;
+                    Op::Append
+                }
+                (Some(':'), Some('=')) => {
+                    self.pos += 2;
+                    Op::Override
+                }
+                // A key without value
+                (None | Some('\n' | ';' | '#' | '}'), _) => {
+                    self.assign(key, Op::Set, vec![String::new()]);
+                    continue;
+                }
+                _ => {
+                    info!("bootconfig: expected a value for [{}]", key);
+                    self.skip_line();
+                    continue;
+                }
+            };
+            match self.read_values() {
+                Some(items) => self.assign(key, op, items),
+                None => {
+                    info!("bootconfig: invalid value for [{}]", key);
+                    self.skip_line();
+                }
             }
-        })
-        .collect()
+        }
+    }
+}
+
+pub fn read_bootconfig(path: &Path) -> ConfigResult<Vec<(String, Value)>> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| ConfigError(ConfigSource::Bootconfig, path.to_owned(), e))?;
+    Ok(parse_bootconfig(&content))
+}
+
+pub fn parse_bootconfig(input: &str) -> Vec<(String, Value)> {
+    let mut parser = BootconfigParser {
+        chars: input.chars().collect(),
+        pos: 0,
+        out: Vec::new(),
+    };
+    parser.parse_block("", false);
+    parser.out
 }
@@ -0,0 +1,227 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use thiserror::Error;
+use Fuseisk::info;
+use crate::bootconfig::{
+    parse_dt, read_bootconfig, read_cmdline, BootConfig, Cmdline, ConfigError,
+};
+use crate::fstab::{fstab_candidates, parse_fstab};
+use crate::init::INIT_BACK;
+
+// Which of the Magisk boot methods the device uses, see README.md.
+//
+// Detection only looks at the boot config and the files of the ramdisk, so it
+// can run against a dumped rootfs on a host as well as on the device.
+
+// Filesystem access detection needs, paths are as seen by init on the device
+pub trait FsProbe {
+    fn exists(&self, path: &str) -> bool;
+    fn read(&self, path: &str) -> Option<Vec<u8>>;
+    // (st_dev, st_ino), following symlinks
+    fn file_id(&self, path: &str) -> Option<(u64, u64)>;
+}
+
+// A root directory, "/" on the device or a dumped ramdisk on a host
+pub struct RootDir {
+    root: String,
+}
+
+impl RootDir {
+    pub fn new(root: &str) -> RootDir {
+        RootDir {
+            root: root.trim_end_matches('/').to_owned(),
+        }
+    }
+
+    fn path(&self, path: &str) -> String {
+        format!("{}{}", self.root, path)
+    }
+}
+
+impl FsProbe for RootDir {
+    fn exists(&self, path: &str) -> bool {
+        Path::new(&self.path(path)).exists()
+    }
+
+    fn read(&self, path: &str) -> Option<Vec<u8>> {
+        fs::read(self.path(path)).ok()
+    }
+
+    fn file_id(&self, path: &str) -> Option<(u64, u64)> {
+        fs::metadata(self.path(path)).ok().map(|m| (m.dev(), m.ino()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMethod {
+    // Method A, legacy ramdisk
+    RootFs,
+    // Method B, the kernel mounts system as root
+    LegacySar,
+    // Method C, 2SI ramdisk switching root to system
+    TwoStage,
+    // A recovery ramdisk, which may also boot the system
+    Recovery,
+}
+
+impl Display for BootMethod {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BootMethod::RootFs => "A (rootfs)",
+            BootMethod::LegacySar => "B (legacy SAR)",
+            BootMethod::TwoStage => "C (2SI)",
+            BootMethod::Recovery => "recovery",
+        })
+    }
+}
+
+pub struct Detection {
+    pub method: BootMethod,
+    // Why the method was chosen
+    pub evidence: Vec<String>,
+}
+
+impl Detection {
+    pub fn print(&self) {
+        info!("boot_method=[{}]", self.method);
+        for reason in &self.evidence {
+            info!("boot_method: {}", reason);
+        }
+    }
+}
+
+fn two_stage_evidence(config: &BootConfig, fs: &dyn FsProbe) -> Vec<String> {
+    let mut reasons = Vec::new();
+
+    for path in ["/first_stage_ramdisk", "/second_stage_resources", "/apex"] {
+        if fs.exists(path) {
+            reasons.push(format!("{} exists", path));
+        }
+    }
+
+    // /init is us at this point, compare against the original init
+    let init = if fs.exists(INIT_BACK) { INIT_BACK } else { "/init" };
+    if let (Some(sys), Some(init)) = (fs.file_id("/system/bin/init"), fs.file_id(init)) {
+        if sys != init {
+            reasons.push("/system/bin/init differs from /init".to_owned());
+        }
+    }
+
+    if config.force_normal_boot {
+        reasons.push("androidboot.force_normal_boot=1".to_owned());
+    }
+
+    if fs.exists(&format!("{}/fstab", config.dt_dir)) {
+        reasons.push(format!("fstab in device tree {}", config.dt_dir));
+    }
+    for path in fstab_candidates(config) {
+        let first_stage = fs
+            .read(&path)
+            .map(|fstab| {
+                parse_fstab(&String::from_utf8_lossy(&fstab))
+                    .iter()
+                    .any(|e| e.fs_mgr_flags.first_stage_mount)
+            })
+            .unwrap_or(false);
+        if first_stage {
+            reasons.push(format!("first_stage_mount in {}", path));
+        }
+    }
+
+    // If we still have no indication, parse the original init and see what's up
+    if reasons.is_empty() {
+        let selinux_setup = fs
+            .read(init)
+            .is_some_and(|data| memchr::memmem::find(&data, b"selinux_setup").is_some());
+        if selinux_setup {
+            reasons.push(format!("{} handles selinux_setup", init));
+        }
+    }
+    reasons
+}
+
+pub fn detect(config: &BootConfig, fs: &dyn FsProbe) -> Detection {
+    let found = |method, reason: &str| Detection {
+        method,
+        evidence: vec![reason.to_owned()],
+    };
+    if config.skip_initramfs {
+        return found(BootMethod::LegacySar, "skip_initramfs in cmdline");
+    }
+    if config.force_normal_boot {
+        return found(BootMethod::TwoStage, "androidboot.force_normal_boot=1");
+    }
+    for path in ["/sbin/recovery", "/system/bin/recovery"] {
+        if fs.exists(path) {
+            return found(BootMethod::Recovery, &format!("{} exists", path));
+        }
+    }
+    let evidence = two_stage_evidence(config, fs);
+    if evidence.is_empty() {
+        return found(BootMethod::RootFs, "no sign of two stage init");
+    }
+    Detection {
+        method: BootMethod::TwoStage,
+        evidence,
+    }
+}
+
+pub const DETECT_USAGE: &str = "\
+Usage: fuseisk detect <rootfs> [--cmdline <file>] [--bootconfig <file>]
+
+Print the boot method init would choose for an extracted ramdisk, along with
+the evidence, without changing anything. The device tree is read from
+<rootfs>/proc/device-tree, like the rest of the files.";
+
+#[derive(Debug, Error)]
+pub enum DetectError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error("{DETECT_USAGE}")]
+    Usage,
+}
+
+// Dry run of the boot method detection against a dumped rootfs
+pub fn detect_main(args: &[String]) -> Result<(), DetectError> {
+    let mut root = None;
+    let mut cmdline = None;
+    let mut bootconfig = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--cmdline" => cmdline = iter.next(),
+            "--bootconfig" => bootconfig = iter.next(),
+            _ if root.is_none() && !arg.starts_with("--") => root = Some(arg),
+            _ => return Err(DetectError::Usage),
+        }
+    }
+    let Some(root) = root else {
+        return Err(DetectError::Usage);
+    };
+
+    let mut config = BootConfig::default();
+    let cmdline = match cmdline {
+        Some(path) => read_cmdline(Path::new(path))?,
+        None => Cmdline::default(),
+    };
+    let bootconfig = match bootconfig {
+        Some(path) => read_bootconfig(Path::new(path))?,
+        None => Vec::new(),
+    };
+    config.find_dt_dir(&cmdline, &bootconfig);
+    let fs = RootDir::new(root);
+    let dt = parse_dt(&fs.path(&config.dt_dir))?;
+    config.apply(dt, cmdline, bootconfig);
+
+    let detection = detect(&config, &fs);
+    println!("boot method: {}", detection.method);
+    for reason in &detection.evidence {
+        println!("  {}", reason);
+    }
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
+use lzma_rust2::{CheckType, LzmaOptions, LzmaReader, LzmaWriter, XzOptions, XzReader, XzWriter};
+use thiserror::Error;
+
+// Ramdisk (and kernel) compression formats found in boot images.
+//
+// All codecs are pure Rust so the static Android builds don't need any C
+// library. The output of compress() is not byte identical to what the vendor
+// tools produce, but it is the same format and is accepted by the kernel.
+// xz and lzma are written like `xz -6 --check=crc32`, the kernel's xz decoder
+// can't verify CRC64.
+
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+const GZIP_OLD_MAGIC: &[u8] = b"\x1f\x9e";
+const XZ_MAGIC: &[u8] = b"\xfd7zXZ\x00";
+const LZMA_MAGIC: &[u8] = b"\x5d\x00\x00";
+const LZ4_MAGIC: &[u8] = b"\x04\x22\x4d\x18";
+const LZ4_LEGACY_MAGIC: &[u8] = b"\x02\x21\x4c\x18";
+const ZSTD_MAGIC: &[u8] = b"\x28\xb5\x2f\xfd";
+
+const LZMA_PRESET: u32 = 6;
+
+// Block size of the lz4 legacy format, the kernel allocates exactly this much
+const LZ4_LEGACY_BLOCK_SIZE: usize = 8 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Unknown,
+    Gzip,
+    Xz,
+    Lzma,
+    Lz4,
+    // The framing of `lz4 -l`, used by most Android kernels
+    Lz4Legacy,
+    Zstd,
+}
+
+#[derive(Debug, Error)]
+pub enum CompressError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("unknown compression format")]
+    UnknownFormat,
+    #[error("corrupted {0} data: {1}")]
+    Corrupted(Format, String),
+}
+
+pub type CompressResult<T> = Result<T, CompressError>;
+
+impl Format {
+    pub fn detect(data: &[u8]) -> Format {
+        if data.starts_with(GZIP_MAGIC) || data.starts_with(GZIP_OLD_MAGIC) {
+            Format::Gzip
+        } else if data.starts_with(XZ_MAGIC) {
+            Format::Xz
+        } else if data.starts_with(LZMA_MAGIC) {
+            Format::Lzma
+        } else if data.starts_with(LZ4_MAGIC) {
+            Format::Lz4
+        } else if data.starts_with(LZ4_LEGACY_MAGIC) {
+            Format::Lz4Legacy
+        } else if data.starts_with(ZSTD_MAGIC) {
+            Format::Zstd
+        } else {
+            Format::Unknown
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        *self != Format::Unknown
+    }
+
+    // Names as accepted by magiskboot's compress=<format>
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "gzip" => Some(Format::Gzip),
+            "xz" => Some(Format::Xz),
+            "lzma" => Some(Format::Lzma),
+            "lz4" => Some(Format::Lz4),
+            "lz4_legacy" => Some(Format::Lz4Legacy),
+            "zstd" => Some(Format::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Unknown => "raw",
+            Format::Gzip => "gzip",
+            Format::Xz => "xz",
+            Format::Lzma => "lzma",
+            Format::Lz4 => "lz4",
+            Format::Lz4Legacy => "lz4_legacy",
+            Format::Zstd => "zstd",
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+fn corrupted<E: Display>(format: Format, e: E) -> CompressError {
+    CompressError::Corrupted(format, e.to_string())
+}
+
+fn lz4_legacy_decode(data: &[u8]) -> CompressResult<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut block = vec![0_u8; LZ4_LEGACY_BLOCK_SIZE];
+    let mut pos = LZ4_LEGACY_MAGIC.len();
+    while let Some(hdr) = data.get(pos..pos + 4) {
+        pos += 4;
+        // Concatenated streams repeat the magic
+        if hdr == LZ4_LEGACY_MAGIC {
+            continue;
+        }
+        let size = u32::from_le_bytes([hdr[0], hdr[1], hdr[2], hdr[3]]) as usize;
+        // Some tools append the uncompressed size after the last block
+        let Some(src) = data.get(pos..pos + size) else {
+            break;
+        };
+        let len = lz4_flex::block::decompress_into(src, &mut block)
+            .map_err(|e| corrupted(Format::Lz4Legacy, e))?;
+        out.extend_from_slice(&block[..len]);
+        pos += size;
+    }
+    Ok(out)
+}
+
+fn lz4_legacy_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = LZ4_LEGACY_MAGIC.to_vec();
+    for chunk in data.chunks(LZ4_LEGACY_BLOCK_SIZE) {
+        let block = lz4_flex::block::compress(chunk);
+        out.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        out.extend_from_slice(&block);
+    }
+    out
+}
+
+pub fn decompress_as(format: Format, data: &[u8]) -> CompressResult<Vec<u8>> {
+    let mut out = Vec::new();
+    match format {
+        Format::Unknown => return Err(CompressError::UnknownFormat),
+        Format::Gzip => {
+            flate2::read::MultiGzDecoder::new(data)
+                .read_to_end(&mut out)
+                .map_err(|e| corrupted(format, e))?;
+        }
+        Format::Xz => {
+            XzReader::new(data, true)
+                .read_to_end(&mut out)
+                .map_err(|e| corrupted(format, e))?;
+        }
+        Format::Lzma => {
+            LzmaReader::new_mem_limit(data, u32::MAX, None)
+                .and_then(|mut dec| dec.read_to_end(&mut out))
+                .map_err(|e| corrupted(format, e))?;
+        }
+        Format::Lz4 => {
+            lz4_flex::frame::FrameDecoder::new(data)
+                .read_to_end(&mut out)
+                .map_err(|e| corrupted(format, e))?;
+        }
+        Format::Lz4Legacy => return lz4_legacy_decode(data),
+        Format::Zstd => {
+            let mut src = data;
+            while !src.is_empty() {
+                ruzstd::decoding::StreamingDecoder::new(&mut src)
+                    .map_err(|e| corrupted(format, e))?
+                    .read_to_end(&mut out)
+                    .map_err(|e| corrupted(format, e))?;
+            }
+        }
+    }
+    Ok(out)
+}
+
+// Decompress data in any supported format, returning the format it was in
+pub fn decompress(data: &[u8]) -> CompressResult<(Format, Vec<u8>)> {
+    let format = Format::detect(data);
+    Ok((format, decompress_as(format, data)?))
+}
+
+pub fn compress(format: Format, data: &[u8]) -> CompressResult<Vec<u8>> {
+    let mut out = Vec::new();
+    match format {
+        Format::Unknown => return Err(CompressError::UnknownFormat),
+        Format::Gzip => {
+            let mut enc = flate2::write::GzEncoder::new(out, flate2::Compression::best());
+            enc.write_all(data)?;
+            out = enc.finish()?;
+        }
+        Format::Xz => {
+            let mut options = XzOptions::with_preset(LZMA_PRESET);
+            options.set_check_sum_type(CheckType::Crc32);
+            let mut enc = XzWriter::new(out, options)?;
+            enc.write_all(data)?;
+            out = enc.finish()?;
+        }
+        Format::Lzma => {
+            let options = LzmaOptions::with_preset(LZMA_PRESET);
+            let mut enc = LzmaWriter::new_use_header(out, &options, Some(data.len() as u64))?;
+            enc.write_all(data)?;
+            out = enc.finish()?;
+        }
+        Format::Lz4 => {
+            let mut enc = lz4_flex::frame::FrameEncoder::new(out);
+            enc.write_all(data)?;
+            out = enc.finish().map_err(|e| corrupted(format, e))?;
+        }
+        Format::Lz4Legacy => out = lz4_legacy_encode(data),
+        Format::Zstd => {
+            out =
+                ruzstd::encoding::compress_to_vec(data, ruzstd::encoding::CompressionLevel::Fastest)
+        }
+    }
+    Ok(out)
+}
@@ -309,7 +309,8 @@ impl Directory {
                 let mut src = e.open_as_dir()?;
                 let dest = dir.open_as_dir_at(e.name())?;
                 src.move_into(&dest)?;
-                return Ok(e.unlink()?);
+                e.unlink()?;
+                continue;
             }
 
             unsafe {
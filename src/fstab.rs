@@ -0,0 +1,234 @@
+use std::fs;
+use std::path::Path;
+use libc::c_ulong;
+use Fuseisk::{debug, info};
+use crate::bootconfig::BootConfig;
+
+// Android fstab files, see system/core/fs_mgr/libfstab/fstab.cpp
+//
+//   <blk_device> <mount_point> <fs_type> <mnt_flags,options> <fs_mgr_flags>
+//
+// First stage init takes them from the device tree when it has an fstab node,
+// otherwise from fstab.<suffix> in the ramdisk.
+
+// Directories an fstab.<suffix> may be in, in the order we look at them
+const FSTAB_DIRS: &[&str] = &["", "/first_stage_ramdisk", "/vendor/etc"];
+
+const MOUNT_FLAGS: &[(&str, c_ulong)] = &[
+    ("defaults", 0),
+    ("rw", 0),
+    ("ro", libc::MS_RDONLY),
+    ("nosuid", libc::MS_NOSUID),
+    ("nodev", libc::MS_NODEV),
+    ("noexec", libc::MS_NOEXEC),
+    ("sync", libc::MS_SYNCHRONOUS),
+    ("remount", libc::MS_REMOUNT),
+    ("dirsync", libc::MS_DIRSYNC),
+    ("noatime", libc::MS_NOATIME),
+    ("nodiratime", libc::MS_NODIRATIME),
+    ("bind", libc::MS_BIND),
+    ("rec", libc::MS_REC),
+    ("unbindable", libc::MS_UNBINDABLE),
+    ("private", libc::MS_PRIVATE),
+    ("slave", libc::MS_SLAVE),
+    ("shared", libc::MS_SHARED),
+    ("lazytime", libc::MS_LAZYTIME),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsMgrFlags {
+    pub wait: bool,
+    pub slotselect: bool,
+    // `avb` or `avb=<vbmeta partition>`
+    pub avb: bool,
+    pub vbmeta_partition: Option<String>,
+    pub first_stage_mount: bool,
+    pub logical: bool,
+    pub recovery_only: bool,
+    // Everything we don't care about, verbatim
+    pub other: Vec<String>,
+}
+
+impl FsMgrFlags {
+    fn parse(flags: &str) -> FsMgrFlags {
+        let mut result = FsMgrFlags::default();
+        for flag in flags.split(',').filter(|f| !f.is_empty()) {
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (flag, None),
+            };
+            match name {
+                "wait" => result.wait = true,
+                "slotselect" => result.slotselect = true,
+                "avb" => {
+                    result.avb = true;
+                    result.vbmeta_partition = value.map(str::to_owned);
+                }
+                "first_stage_mount" => result.first_stage_mount = true,
+                "logical" => result.logical = true,
+                "recoveryonly" => result.recovery_only = true,
+                _ => result.other.push(flag.to_owned()),
+            }
+        }
+        result
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FstabEntry {
+    // A path for physical partitions, the partition name when `logical`
+    pub blk_device: String,
+    pub mount_point: String,
+    pub fs_type: String,
+    // MS_* mount flags
+    pub flags: c_ulong,
+    // Filesystem specific options, passed as mount data
+    pub fs_options: String,
+    pub fs_mgr_flags: FsMgrFlags,
+}
+
+impl FstabEntry {
+    fn parse(line: &str) -> Option<FstabEntry> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [blk_device, mount_point, fs_type, mnt_flags, fs_mgr_flags] = fields[..] else {
+            return None;
+        };
+        let mut flags = 0;
+        let mut options = Vec::new();
+        for opt in mnt_flags.split(',').filter(|o| !o.is_empty()) {
+            match MOUNT_FLAGS.iter().find(|(name, _)| *name == opt) {
+                Some((_, flag)) => flags |= flag,
+                None => options.push(opt),
+            }
+        }
+        Some(FstabEntry {
+            blk_device: blk_device.to_owned(),
+            mount_point: mount_point.to_owned(),
+            fs_type: fs_type.to_owned(),
+            flags,
+            fs_options: options.join(","),
+            fs_mgr_flags: FsMgrFlags::parse(fs_mgr_flags),
+        })
+    }
+
+    // The partition name, without the /dev/block/by-name/ prefix
+    pub fn partname(&self) -> &str {
+        self.blk_device.rsplit('/').next().unwrap_or(&self.blk_device)
+    }
+
+    // A/B devices list the partition without slot suffix
+    pub fn resolve_slot(&mut self, slot: &str) {
+        if self.fs_mgr_flags.slotselect {
+            self.blk_device.push_str(slot);
+            self.fs_mgr_flags.slotselect = false;
+        }
+    }
+}
+
+pub fn parse_fstab(content: &str) -> Vec<FstabEntry> {
+    let mut entries = Vec::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match FstabEntry::parse(line) {
+            Some(entry) => entries.push(entry),
+            None => info!("fstab: skip invalid entry [{}]", line),
+        }
+    }
+    entries
+}
+
+// Each child of <dt_dir>/fstab is an entry, its properties are the fstab fields
+pub fn read_dt_fstab(dt_dir: &str) -> Vec<FstabEntry> {
+    let fstab_dir = Path::new(dt_dir).join("fstab");
+    let Ok(dir) = fs::read_dir(&fstab_dir) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = dir
+        .flatten()
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+        .filter_map(|e| e.file_name().into_string().ok())
+        .collect();
+    names.sort();
+
+    let mut entries = Vec::new();
+    for name in names {
+        let node = fstab_dir.join(&name);
+        let read = |prop: &str| {
+            fs::read(node.join(prop))
+                .ok()
+                .map(|data| String::from_utf8_lossy(&data).trim_end_matches('\0').trim().to_owned())
+        };
+        if read("status").is_some_and(|s| s != "okay" && s != "ok") {
+            continue;
+        }
+        let Some(dev) = read("dev") else {
+            info!("fstab: no dev in {}", node.display());
+            continue;
+        };
+        let line = format!(
+            "{} {} {} {} {}",
+            dev,
+            read("mnt_point").unwrap_or_else(|| format!("/{}", name)),
+            read("type").unwrap_or_default(),
+            read("mnt_flags").unwrap_or_default(),
+            read("fsmgr_flags").unwrap_or_default(),
+        );
+        match FstabEntry::parse(&line) {
+            Some(entry) => entries.push(entry),
+            None => info!("fstab: skip invalid DT entry [{}]", name),
+        }
+    }
+    entries
+}
+
+// Where an fstab.<suffix> may be, named after fstab_suffix or the hardware
+pub fn fstab_candidates(config: &BootConfig) -> Vec<String> {
+    let mut paths = Vec::new();
+    for suffix in [&config.fstab_suffix, &config.hardware, &config.hardware_plat] {
+        if suffix.is_empty() {
+            continue;
+        }
+        for dir in FSTAB_DIRS {
+            let path = format!("{}/fstab.{}", dir, suffix);
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+    }
+    paths
+}
+
+// Existing fstab.<suffix> files under root
+pub fn find_fstabs(root: &str, config: &BootConfig) -> Vec<String> {
+    fstab_candidates(config)
+        .into_iter()
+        .map(|path| format!("{}{}", root, path))
+        .filter(|path| Path::new(path).exists())
+        .collect()
+}
+
+// The fstab first stage init would use, with slotselect resolved
+pub fn first_stage_fstab(root: &str, config: &BootConfig) -> Vec<FstabEntry> {
+    let mut entries = read_dt_fstab(&config.dt_dir);
+    for path in find_fstabs(root, config) {
+        let Ok(content) = fs::read_to_string(&path) else {
+            continue;
+        };
+        debug!("fstab: load {}", path);
+        // The device tree comes first, then the first file listing a mount point.
+        // A file may list a mount point more than once, for different filesystems.
+        let known: Vec<String> = entries.iter().map(|e| e.mount_point.clone()).collect();
+        entries.extend(
+            parse_fstab(&content)
+                .into_iter()
+                .filter(|e| !known.contains(&e.mount_point)),
+        );
+    }
+    for entry in &mut entries {
+        entry.resolve_slot(&config.slot);
+    }
+    entries
+}
@@ -1,149 +1,249 @@
-// use clap::{crate_version, Arg, ArgAction, Command};
-use fuser::{
-    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    Request,
-};
-use libc::ENOENT;
-use std::ffi::OsStr;
-use std::time::{Duration, UNIX_EPOCH};
-
-const TTL: Duration = Duration::from_secs(1); // 1 second
-
-const HELLO_DIR_ATTR: FileAttr = FileAttr {
-    ino: 1,
-    size: 0,
-    blocks: 0,
-    atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-    mtime: UNIX_EPOCH,
-    ctime: UNIX_EPOCH,
-    crtime: UNIX_EPOCH,
-    kind: FileType::Directory,
-    perm: 0o755,
-    nlink: 2,
-    uid: 501,
-    gid: 20,
-    rdev: 0,
-    flags: 0,
-    blksize: 512,
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use libc::{
+    _exit, fork, EIO, ENOENT, EROFS, MS_NODEV, MS_NOSUID, MS_RDONLY, O_ACCMODE, O_RDONLY, S_IFDIR,
+    S_IFMT, S_IFREG,
 };
+use Fuseisk::cstr::Utf8CStr;
+use Fuseisk::fusedev::{Attr, DirEntry, Filesystem, FuseDev, Session, StatFs, FUSE_ROOT_ID};
+use Fuseisk::logging::setup_stderr_log;
+use Fuseisk::result::{LoggedError, LoggedResult, ResultExt};
+use Fuseisk::{cstr, debug, info, MountStack};
 
-const HELLO_TXT_CONTENT: &str = "Hello World!\n";
-
-const HELLO_TXT_ATTR: FileAttr = FileAttr {
-    ino: 2,
-    size: 13,
-    blocks: 1,
-    atime: UNIX_EPOCH, // 1970-01-01 00:00:00
-    mtime: UNIX_EPOCH,
-    ctime: UNIX_EPOCH,
-    crtime: UNIX_EPOCH,
-    kind: FileType::RegularFile,
-    perm: 0o644,
-    nlink: 1,
-    uid: 501,
-    gid: 20,
-    rdev: 0,
-    flags: 0,
-    blksize: 512,
-};
+// A read-only view of a real directory, served over FUSE.
+//
+// Every request is passed through to the backing directory. This is the base
+// for hiding and overlaying system files, which only need to change what
+// lookup and readdir return.
+
+pub const FUSE_USAGE: &str = "\
+Usage: fuseisk fuse <dir> <mountpoint>
+
+Mount a read-only FUSE view of <dir> at <mountpoint>, the way init does for
+each line of overlay.d/fuse.conf. The filesystem is served in the background
+until it is unmounted.";
+
+fn not_found() -> io::Error {
+    io::Error::from_raw_os_error(ENOENT)
+}
 
-struct HelloFS;
+pub struct PassthroughFs {
+    // Backing path of each inode given to the kernel, FUSE_ROOT_ID first.
+    // The tree is read-only and bounded, so inodes are never forgotten.
+    paths: Vec<PathBuf>,
+    inodes: HashMap<PathBuf, u64>,
+    files: HashMap<u64, File>,
+    next_fh: u64,
+}
 
-impl Filesystem for HelloFS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        if parent == 1 && name.to_str() == Some("hello.txt") {
-            reply.entry(&TTL, &HELLO_TXT_ATTR, 0);
-        } else {
-            reply.error(ENOENT);
+impl PassthroughFs {
+    pub fn new(root: &Path) -> PassthroughFs {
+        PassthroughFs {
+            paths: vec![root.to_owned()],
+            inodes: HashMap::from([(root.to_owned(), FUSE_ROOT_ID)]),
+            files: HashMap::new(),
+            next_fh: 1,
         }
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
-        match ino {
-            1 => reply.attr(&TTL, &HELLO_DIR_ATTR),
-            2 => reply.attr(&TTL, &HELLO_TXT_ATTR),
-            _ => reply.error(ENOENT),
+    fn path(&self, ino: u64) -> io::Result<&Path> {
+        ino.checked_sub(FUSE_ROOT_ID)
+            .and_then(|i| self.paths.get(i as usize))
+            .map(PathBuf::as_path)
+            .ok_or_else(not_found)
+    }
+
+    fn inode(&mut self, path: PathBuf) -> u64 {
+        if let Some(ino) = self.inodes.get(&path) {
+            return *ino;
         }
+        let ino = FUSE_ROOT_ID + self.paths.len() as u64;
+        self.paths.push(path.clone());
+        self.inodes.insert(path, ino);
+        ino
+    }
+}
+
+impl Filesystem for PassthroughFs {
+    fn lookup(&mut self, parent: u64, name: &OsStr) -> io::Result<Attr> {
+        let path = self.path(parent)?.join(name);
+        let meta = fs::symlink_metadata(&path)?;
+        Ok(Attr::from_metadata(self.inode(path), &meta))
+    }
+
+    fn getattr(&mut self, ino: u64) -> io::Result<Attr> {
+        Ok(Attr::from_metadata(ino, &fs::symlink_metadata(self.path(ino)?)?))
+    }
+
+    fn readlink(&mut self, ino: u64) -> io::Result<Vec<u8>> {
+        Ok(fs::read_link(self.path(ino)?)?.into_os_string().into_encoded_bytes())
     }
 
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        _size: u32,
-        _flags: i32,
-        _lock: Option<u64>,
-        reply: ReplyData,
-    ) {
-        if ino == 2 {
-            reply.data(&HELLO_TXT_CONTENT.as_bytes()[offset as usize..]);
-        } else {
-            reply.error(ENOENT);
+    fn open(&mut self, ino: u64, flags: i32) -> io::Result<u64> {
+        if flags & O_ACCMODE != O_RDONLY {
+            return Err(io::Error::from_raw_os_error(EROFS));
         }
+        let file = File::open(self.path(ino)?)?;
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.files.insert(fh, file);
+        Ok(fh)
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        if ino != 1 {
-            reply.error(ENOENT);
-            return;
+    fn read(&mut self, _ino: u64, fh: u64, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let file = self
+            .files
+            .get(&fh)
+            .ok_or_else(|| io::Error::from_raw_os_error(EIO))?;
+        let mut buf = vec![0; size as usize];
+        let mut len = 0;
+        while len < buf.len() {
+            match file.read_at(&mut buf[len..], offset + len as u64)? {
+                0 => break,
+                n => len += n,
+            }
         }
+        buf.truncate(len);
+        Ok(buf)
+    }
 
-        let entries = vec![
-            (1, FileType::Directory, "."),
-            (1, FileType::Directory, ".."),
-            (2, FileType::RegularFile, "hello.txt"),
-        ];
+    fn release(&mut self, _ino: u64, fh: u64) {
+        self.files.remove(&fh);
+    }
 
-        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
-            // i + 1 means the index of the next entry
-            if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
-                break;
-            }
+    // "." and ".." first, then the backing directory sorted by name
+    #[allow(clippy::unnecessary_cast)]
+    fn readdir(&mut self, ino: u64) -> io::Result<Vec<DirEntry>> {
+        let path = self.path(ino)?.to_owned();
+        let parent = match path.parent() {
+            Some(parent) if ino != FUSE_ROOT_ID => self.inode(parent.to_owned()),
+            _ => FUSE_ROOT_ID,
+        };
+        let dir = |ino, name: &str| DirEntry {
+            ino,
+            kind: S_IFDIR as u32,
+            name: name.into(),
+        };
+        let mut entries = vec![dir(ino, "."), dir(parent, "..")];
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let kind = entry
+                .metadata()
+                .map_or(S_IFREG as u32, |m| m.mode() & S_IFMT as u32);
+            let name = entry.file_name();
+            entries.push(DirEntry {
+                ino: self.inode(path.join(&name)),
+                kind,
+                name,
+            });
+        }
+        entries[2..].sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    fn statfs(&mut self, ino: u64) -> io::Result<StatFs> {
+        let path = self.path(ino)?.as_os_str().as_encoded_bytes().to_vec();
+        let path = CString::new(path).map_err(|_| not_found())?;
+        let mut st = MaybeUninit::<libc::statvfs>::uninit();
+        if unsafe { libc::statvfs(path.as_ptr(), st.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let st = unsafe { st.assume_init() };
+        Ok(StatFs {
+            blocks: st.f_blocks as u64,
+            bfree: st.f_bfree as u64,
+            bavail: st.f_bavail as u64,
+            files: st.f_files as u64,
+            ffree: st.f_ffree as u64,
+            bsize: st.f_bsize as u32,
+            namelen: st.f_namemax as u32,
+            frsize: st.f_frsize as u32,
+        })
+    }
+}
+
+// Serve `source` read-only at `target`. The filesystem is served by a forked
+// child, so it outlives the exec of the original init.
+pub fn mount_passthrough(
+    source: &str,
+    target: &Utf8CStr,
+    mounts: &mut MountStack,
+) -> LoggedResult<()> {
+    let dev = FuseDev::open()?;
+    let root_mode = target.follow_link().get_attr()?.st.st_mode & S_IFMT;
+    let mut options = format!(
+        "fd={},rootmode={:o},user_id=0,group_id=0,allow_other,default_permissions",
+        dev.as_raw_fd(),
+        root_mode
+    );
+    target.mount_fs(
+        cstr!("fuse"),
+        cstr!("fuseisk"),
+        MS_RDONLY | MS_NOSUID | MS_NODEV,
+        Some(Utf8CStr::from_string(&mut options)),
+    )?;
+    debug!("FUSE mount {} -> {}", source, target);
+
+    match unsafe { fork() } {
+        0 => {
+            let mut session = Session::new(PassthroughFs::new(Path::new(source)));
+            session.run(&dev).log_ok();
+            unsafe { _exit(0) }
+        }
+        pid if pid < 0 => {
+            info!("Cannot fork FUSE server for {}", target);
+            target.unmount().log_ok();
+            Err(LoggedError::default())
+        }
+        // Our copy of the device is closed on return, the mount stays with
+        // the child
+        _ => {
+            mounts.push_kept(target);
+            Ok(())
         }
-        reply.ok();
     }
 }
 
-// fn main() {
-//     let matches = Command::new("hello")
-//         .version(crate_version!())
-//         .author("Christopher Berner")
-//         .arg(
-//             Arg::new("MOUNT_POINT")
-//                 .required(true)
-//                 .index(1)
-//                 .help("Act as a client, and mount FUSE at given path"),
-//         )
-//         .arg(
-//             Arg::new("auto_unmount")
-//                 .long("auto_unmount")
-//                 .action(ArgAction::SetTrue)
-//                 .help("Automatically unmount on process exit"),
-//         )
-//         .arg(
-//             Arg::new("allow-root")
-//                 .long("allow-root")
-//                 .action(ArgAction::SetTrue)
-//                 .help("Allow root user to access filesystem"),
-//         )
-//         .get_matches();
-//     env_logger::init();
-//     let mountpoint = matches.get_one::<String>("MOUNT_POINT").unwrap();
-//     let mut options = vec![MountOption::RO, MountOption::FSName("hello".to_string())];
-//     if matches.get_flag("auto_unmount") {
-//         options.push(MountOption::AutoUnmount);
-//     }
-//     if matches.get_flag("allow-root") {
-//         options.push(MountOption::AllowRoot);
-//     }
-//     fuser::mount2(HelloFS, mountpoint, &options).unwrap();
-// }
\ No newline at end of file
+// Listed in overlay.d, one `<dir> <mountpoint>` per line, each mounted by init
+pub const FUSE_CONF: &str = "fuse.conf";
+
+pub fn parse_fuse_conf(content: &str) -> Vec<(String, String)> {
+    let mut result = Vec::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [source, target] => result.push((source.to_owned(), target.to_owned())),
+            _ => info!("{}: expected <dir> <mountpoint>, found {}", FUSE_CONF, line),
+        }
+    }
+    result
+}
+
+// Mount everything FUSE_CONF in `dir` lists, returning its path if there is one
+pub fn mount_fuse_conf(dir: &str, mounts: &mut MountStack) -> Option<PathBuf> {
+    let path = Path::new(dir).join(FUSE_CONF);
+    let content = fs::read_to_string(&path).ok()?;
+    for (source, mut target) in parse_fuse_conf(&content) {
+        mount_passthrough(&source, Utf8CStr::from_string(&mut target), mounts).log_ok();
+    }
+    Some(path)
+}
+
+pub fn fuse_main(args: &[String]) -> LoggedResult<()> {
+    let [source, target] = args else {
+        eprintln!("{}", FUSE_USAGE);
+        return Err(LoggedError::default());
+    };
+    setup_stderr_log();
+    let mut target = target.clone();
+    mount_passthrough(source, Utf8CStr::from_string(&mut target), &mut MountStack::default())
+}
@@ -0,0 +1,567 @@
+use std::ffi::{OsStr, OsString};
+use std::fs::Metadata;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use libc::{
+    makedev, mknod, EAGAIN, EINTR, EIO, ENODEV, ENOENT, ENOSYS, EPROTO, O_CLOEXEC, O_RDWR,
+    S_IFCHR, S_IFMT,
+};
+use crate::cstr::Utf8CStr;
+use crate::result::{LibcReturn, OsResult};
+use crate::{cstr, debug};
+
+// The FUSE kernel protocol, served straight from /dev/fuse. See
+// include/uapi/linux/fuse.h for the structs encoded here.
+//
+// Each read of the device returns one request: a fuse_in_header followed by
+// the arguments of its opcode. Each write answers one request with a
+// fuse_out_header followed by the result. Everything is in host byte order.
+
+const FUSE_DEV: &str = "/dev/fuse";
+const FUSE_DEV_MAJOR: u32 = 10;
+const FUSE_DEV_MINOR: u32 = 229;
+
+pub const FUSE_ROOT_ID: u64 = 1;
+
+// We speak 7.31 and settle for the kernel's minor if it is older. 7.9 is
+// where fuse_attr and fuse_entry_out got their current layout.
+const KERNEL_VERSION: u32 = 7;
+const KERNEL_MINOR_VERSION: u32 = 31;
+const MIN_MINOR_VERSION: u32 = 9;
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_READLINK: u32 = 5;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+const FUSE_READDIRPLUS: u32 = 44;
+
+// INIT flags we accept when the kernel offers them
+const FUSE_ASYNC_READ: u32 = 1 << 0;
+const FUSE_DO_READDIRPLUS: u32 = 1 << 13;
+
+const IN_HEADER_SIZE: usize = 40;
+const OUT_HEADER_SIZE: usize = 16;
+const DIRENT_SIZE: usize = 24;
+const ENTRY_OUT_SIZE: usize = 128;
+
+// Nothing is ever written, but the kernel refuses reads into a buffer that
+// can't hold a WRITE of max_write bytes, or is below FUSE_MIN_READ_BUFFER
+const MAX_WRITE: u32 = 4096;
+const BUFFER_SIZE: usize = 8192;
+
+// How long the kernel may cache entries and attributes, in seconds
+const TTL: u64 = 1;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Attr {
+    pub ino: u64,
+    pub size: u64,
+    pub blocks: u64,
+    pub atime: i64,
+    pub atime_nsec: u32,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    pub ctime: i64,
+    pub ctime_nsec: u32,
+    // File type and permission bits, as in st_mode
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u64,
+    pub blksize: u32,
+}
+
+impl Attr {
+    // The attributes of a real file, under our inode number
+    pub fn from_metadata(ino: u64, meta: &Metadata) -> Attr {
+        Attr {
+            ino,
+            size: meta.size(),
+            blocks: meta.blocks(),
+            atime: meta.atime(),
+            atime_nsec: meta.atime_nsec() as u32,
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec() as u32,
+            ctime: meta.ctime(),
+            ctime_nsec: meta.ctime_nsec() as u32,
+            mode: meta.mode(),
+            nlink: meta.nlink() as u32,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            rdev: meta.rdev(),
+            blksize: meta.blksize() as u32,
+        }
+    }
+
+    // One of the S_IF* file types
+    #[allow(clippy::unnecessary_cast)]
+    pub fn kind(&self) -> u32 {
+        self.mode & S_IFMT as u32
+    }
+
+    // fuse_attr
+    fn encode(&self, out: &mut Vec<u8>) {
+        put_u64(out, self.ino);
+        put_u64(out, self.size);
+        put_u64(out, self.blocks);
+        put_u64(out, self.atime as u64);
+        put_u64(out, self.mtime as u64);
+        put_u64(out, self.ctime as u64);
+        put_u32(out, self.atime_nsec);
+        put_u32(out, self.mtime_nsec);
+        put_u32(out, self.ctime_nsec);
+        put_u32(out, self.mode);
+        put_u32(out, self.nlink);
+        put_u32(out, self.uid);
+        put_u32(out, self.gid);
+        put_u32(out, encode_dev(self.rdev));
+        put_u32(out, self.blksize);
+        // flags
+        put_u32(out, 0);
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatFs {
+    pub blocks: u64,
+    pub bfree: u64,
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub namelen: u32,
+    pub frsize: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub ino: u64,
+    // One of the S_IF* file types
+    pub kind: u32,
+    pub name: OsString,
+}
+
+// What a FUSE filesystem implements, the Session takes care of the protocol.
+// Errors are answered with their errno, EIO when they don't have one.
+pub trait Filesystem {
+    // Every successful lookup is one reference the kernel holds on the inode,
+    // until it is given back by forget
+    fn lookup(&mut self, parent: u64, name: &OsStr) -> io::Result<Attr>;
+
+    fn forget(&mut self, _ino: u64, _nlookup: u64) {}
+
+    fn getattr(&mut self, ino: u64) -> io::Result<Attr>;
+
+    fn readlink(&mut self, ino: u64) -> io::Result<Vec<u8>>;
+
+    // Returns the file handle passed to read and release
+    fn open(&mut self, ino: u64, flags: i32) -> io::Result<u64>;
+
+    fn read(&mut self, ino: u64, fh: u64, offset: u64, size: u32) -> io::Result<Vec<u8>>;
+
+    fn release(&mut self, ino: u64, fh: u64);
+
+    // The whole directory, including "." and "..". The order must be stable,
+    // the kernel continues listing from an index into it.
+    fn readdir(&mut self, ino: u64) -> io::Result<Vec<DirEntry>>;
+
+    fn statfs(&mut self, _ino: u64) -> io::Result<StatFs> {
+        Err(io::Error::from_raw_os_error(ENOSYS))
+    }
+}
+
+fn put_u16(out: &mut Vec<u8>, val: u16) {
+    out.extend_from_slice(&val.to_ne_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, val: u32) {
+    out.extend_from_slice(&val.to_ne_bytes());
+}
+
+fn put_u64(out: &mut Vec<u8>, val: u64) {
+    out.extend_from_slice(&val.to_ne_bytes());
+}
+
+// The kernel decodes fuse_attr.rdev with new_decode_dev()
+fn encode_dev(dev: u64) -> u32 {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    ((minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)) as u32
+}
+
+fn errno(e: &io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(EIO)
+}
+
+fn is_dot(name: &OsStr) -> bool {
+    name == "." || name == ".."
+}
+
+// Size of a fuse_dirent, or a fuse_direntplus, padded to 8 bytes
+fn dirent_size(name: &OsStr, plus: bool) -> usize {
+    let size = DIRENT_SIZE + name.len();
+    let size = (size + 7) & !7;
+    if plus {
+        ENTRY_OUT_SIZE + size
+    } else {
+        size
+    }
+}
+
+// fuse_entry_out, all zeros tells readdirplus to skip the entry
+fn encode_entry(attr: Option<&Attr>, out: &mut Vec<u8>) {
+    let Some(attr) = attr else {
+        out.resize(out.len() + ENTRY_OUT_SIZE, 0);
+        return;
+    };
+    put_u64(out, attr.ino);
+    // generation, inode numbers are never reused
+    put_u64(out, 0);
+    put_u64(out, TTL);
+    put_u64(out, TTL);
+    put_u32(out, 0);
+    put_u32(out, 0);
+    attr.encode(out);
+}
+
+// fuse_attr_out
+fn encode_attr(attr: &Attr, out: &mut Vec<u8>) {
+    put_u64(out, TTL);
+    put_u32(out, 0);
+    put_u32(out, 0);
+    attr.encode(out);
+}
+
+// fuse_open_out
+fn encode_open(fh: u64, out: &mut Vec<u8>) {
+    put_u64(out, fh);
+    put_u32(out, 0);
+    put_u32(out, 0);
+}
+
+// fuse_kstatfs
+fn encode_statfs(st: &StatFs, out: &mut Vec<u8>) {
+    put_u64(out, st.blocks);
+    put_u64(out, st.bfree);
+    put_u64(out, st.bavail);
+    put_u64(out, st.files);
+    put_u64(out, st.ffree);
+    put_u32(out, st.bsize);
+    put_u32(out, st.namelen);
+    put_u32(out, st.frsize);
+    out.resize(out.len() + 4 + 6 * 4, 0);
+}
+
+// The arguments of a request, taken front to back
+struct Args<'a>(&'a [u8]);
+
+impl<'a> Args<'a> {
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(io::Error::from_raw_os_error(EIO));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_ne_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_ne_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    // A NUL terminated file name
+    fn name(&mut self) -> io::Result<&'a OsStr> {
+        let len = self
+            .0
+            .iter()
+            .position(|c| *c == 0)
+            .ok_or_else(|| io::Error::from_raw_os_error(EIO))?;
+        let name = self.bytes(len + 1)?;
+        Ok(OsStr::from_bytes(&name[..len]))
+    }
+}
+
+pub struct FuseDev {
+    fd: OwnedFd,
+}
+
+impl FuseDev {
+    // With a tmpfs on /dev the kernel hasn't created the node for us
+    pub fn open() -> OsResult<'static, FuseDev> {
+        let dev = cstr!(FUSE_DEV);
+        if !dev.exists() {
+            unsafe {
+                mknod(
+                    dev.as_ptr(),
+                    S_IFCHR | 0o666,
+                    makedev(FUSE_DEV_MAJOR, FUSE_DEV_MINOR),
+                )
+            }
+            .check_os_err("mknod", Some(FUSE_DEV), None)?;
+        }
+        Ok(FuseDev {
+            fd: dev.open(O_RDWR | O_CLOEXEC)?.into(),
+        })
+    }
+
+    fn read(&self, buf: &mut [u8]) -> OsResult<'static, usize> {
+        let len = unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) }
+            .as_os_result("read", Some(FUSE_DEV), None)?;
+        Ok(len as usize)
+    }
+
+    fn write(&self, reply: &[u8]) -> OsResult<'static, ()> {
+        unsafe { libc::write(self.fd.as_raw_fd(), reply.as_ptr().cast(), reply.len()) }
+            .check_os_err("write", Some(FUSE_DEV), None)
+    }
+}
+
+impl AsRawFd for FuseDev {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+pub struct Session<F> {
+    fs: F,
+    // The negotiated minor version, 0 until INIT
+    minor: u32,
+    destroyed: bool,
+}
+
+impl<F: Filesystem> Session<F> {
+    pub fn new(fs: F) -> Session<F> {
+        Session {
+            fs,
+            minor: 0,
+            destroyed: false,
+        }
+    }
+
+    // Answer requests until the filesystem is unmounted
+    pub fn run(&mut self, dev: &FuseDev) -> OsResult<'static, ()> {
+        let mut buf = vec![0; BUFFER_SIZE];
+        while !self.destroyed {
+            let len = match dev.read(&mut buf) {
+                Ok(len) => len,
+                // The request was interrupted before we got it
+                Err(e) if matches!(e.code(), ENOENT | EINTR | EAGAIN) => continue,
+                Err(e) if e.code() == ENODEV => break,
+                Err(e) => return Err(e),
+            };
+            if let Some(reply) = self.handle(&buf[..len]) {
+                match dev.write(&reply) {
+                    // The request was interrupted while we were at it
+                    Err(e) if e.code() == ENOENT => {}
+                    res => res?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The reply to one request, if it has one
+    pub fn handle(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        let mut header = Args(request);
+        let len = header.u32().ok()? as usize;
+        let opcode = header.u32().ok()?;
+        let unique = header.u64().ok()?;
+        // Malformed past the unique, the kernel still waits for an answer
+        let body = header
+            .u64()
+            .ok()
+            .zip(request.get(IN_HEADER_SIZE..len).map(Args));
+
+        match opcode {
+            FUSE_FORGET => {
+                if let Some((nodeid, mut args)) = body {
+                    if let Ok(nlookup) = args.u64() {
+                        self.fs.forget(nodeid, nlookup);
+                    }
+                }
+                return None;
+            }
+            FUSE_BATCH_FORGET => {
+                if let Some((_, args)) = body {
+                    self.batch_forget(args).ok();
+                }
+                return None;
+            }
+            // Requests are answered one at a time, there is nothing to interrupt
+            FUSE_INTERRUPT => return None,
+            _ => {}
+        }
+
+        let mut out = vec![0; OUT_HEADER_SIZE];
+        let result = match body {
+            Some((nodeid, args)) => self.dispatch(opcode, nodeid, args, &mut out),
+            None => Err(io::Error::from_raw_os_error(EIO)),
+        };
+        let error = match result {
+            Ok(()) => 0,
+            Err(e) => {
+                out.truncate(OUT_HEADER_SIZE);
+                -errno(&e)
+            }
+        };
+        let out_len = out.len() as u32;
+        out[0..4].copy_from_slice(&out_len.to_ne_bytes());
+        out[4..8].copy_from_slice(&error.to_ne_bytes());
+        out[8..16].copy_from_slice(&unique.to_ne_bytes());
+        Some(out)
+    }
+
+    fn dispatch(
+        &mut self,
+        opcode: u32,
+        nodeid: u64,
+        mut args: Args,
+        out: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        if self.minor == 0 && opcode != FUSE_INIT {
+            return Err(io::Error::from_raw_os_error(EIO));
+        }
+        match opcode {
+            FUSE_INIT => self.init(args, out)?,
+            FUSE_LOOKUP => {
+                let attr = self.fs.lookup(nodeid, args.name()?)?;
+                encode_entry(Some(&attr), out);
+            }
+            FUSE_GETATTR => encode_attr(&self.fs.getattr(nodeid)?, out),
+            FUSE_READLINK => out.extend(self.fs.readlink(nodeid)?),
+            FUSE_OPEN => {
+                let flags = args.u32()? as i32;
+                encode_open(self.fs.open(nodeid, flags)?, out);
+            }
+            // Directories are listed by inode, they need no handle
+            FUSE_OPENDIR => encode_open(0, out),
+            FUSE_READ => {
+                let fh = args.u64()?;
+                let offset = args.u64()?;
+                let size = args.u32()?;
+                out.extend(self.fs.read(nodeid, fh, offset, size)?);
+            }
+            FUSE_READDIR | FUSE_READDIRPLUS => {
+                let _fh = args.u64()?;
+                let offset = args.u64()?;
+                let size = args.u32()?;
+                self.readdir(nodeid, offset, size, opcode == FUSE_READDIRPLUS, out)?;
+            }
+            FUSE_RELEASE => self.fs.release(nodeid, args.u64()?),
+            FUSE_RELEASEDIR | FUSE_FLUSH => {}
+            FUSE_STATFS => encode_statfs(&self.fs.statfs(nodeid)?, out),
+            FUSE_DESTROY => self.destroyed = true,
+            _ => return Err(io::Error::from_raw_os_error(ENOSYS)),
+        }
+        Ok(())
+    }
+
+    // fuse_init_in to fuse_init_out
+    fn init(&mut self, mut args: Args, out: &mut Vec<u8>) -> io::Result<()> {
+        let major = args.u32()?;
+        let minor = args.u32()?;
+        // A newer kernel sends INIT again with the version we answer, as in libfuse
+        if major > KERNEL_VERSION {
+            debug!(
+                "FUSE protocol {}.{}, asking for {}",
+                major, minor, KERNEL_VERSION
+            );
+            put_u32(out, KERNEL_VERSION);
+            put_u32(out, KERNEL_MINOR_VERSION);
+            out.resize(OUT_HEADER_SIZE + 64, 0);
+            return Ok(());
+        }
+        let max_readahead = args.u32()?;
+        let flags = args.u32()?;
+        if major < KERNEL_VERSION || minor < MIN_MINOR_VERSION {
+            debug!("FUSE protocol {}.{} is not supported", major, minor);
+            return Err(io::Error::from_raw_os_error(EPROTO));
+        }
+        self.minor = minor.min(KERNEL_MINOR_VERSION);
+        debug!("FUSE protocol {}.{}", major, self.minor);
+
+        put_u32(out, KERNEL_VERSION);
+        put_u32(out, self.minor);
+        put_u32(out, max_readahead);
+        put_u32(out, flags & (FUSE_ASYNC_READ | FUSE_DO_READDIRPLUS));
+        // max_background and congestion_threshold, as in libfuse
+        put_u16(out, 12);
+        put_u16(out, 9);
+        put_u32(out, MAX_WRITE);
+        // Before 7.23 the struct ends here
+        if self.minor >= 23 {
+            // time_gran
+            put_u32(out, 1);
+            out.resize(OUT_HEADER_SIZE + 64, 0);
+        }
+        Ok(())
+    }
+
+    fn batch_forget(&mut self, mut args: Args) -> io::Result<()> {
+        let count = args.u32()?;
+        let _dummy = args.u32()?;
+        for _ in 0..count {
+            let ino = args.u64()?;
+            let nlookup = args.u64()?;
+            self.fs.forget(ino, nlookup);
+        }
+        Ok(())
+    }
+
+    // Entries from `offset` on that fit in `size` bytes. The offset of each
+    // entry is where the next call continues.
+    fn readdir(
+        &mut self,
+        ino: u64,
+        offset: u64,
+        size: u32,
+        plus: bool,
+        out: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        let end = out.len() + size as usize;
+        let entries = self.fs.readdir(ino)?;
+        for (i, entry) in entries.iter().enumerate().skip(offset as usize) {
+            if out.len() + dirent_size(&entry.name, plus) > end {
+                break;
+            }
+            if plus {
+                // An entry with attributes counts as a lookup, except for "."
+                // and "..", which the kernel never takes a reference on
+                let attr = if is_dot(&entry.name) {
+                    None
+                } else {
+                    self.fs.lookup(ino, &entry.name).ok()
+                };
+                encode_entry(attr.as_ref(), out);
+            }
+            // fuse_dirent
+            let name = entry.name.as_bytes();
+            put_u64(out, entry.ino);
+            put_u64(out, i as u64 + 1);
+            put_u32(out, name.len() as u32);
+            put_u32(out, entry.kind >> 12);
+            out.extend_from_slice(name);
+            out.resize((out.len() + 7) & !7, 0);
+        }
+        Ok(())
+    }
+}
@@ -1,18 +1,26 @@
 use std::io::Write;
-use libc::{execve, exit, fork, getpid, mount, sleep, O_CLOEXEC, O_CREAT, O_RDONLY, O_WRONLY};
+use libc::{execve, exit, fork, getpid, sleep, MS_RDONLY, O_CLOEXEC, O_CREAT, O_RDONLY, O_WRONLY};
 use std::ffi::{c_char, c_void, CStr, CString};
 use std::os::unix::fs::PermissionsExt;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io;
-use std::ptr::null;
+use std::mem::MaybeUninit;
+use std::path::PathBuf;
 use Fuseisk::cstr::Utf8CStr;
 use Fuseisk::{cstr, debug, info, raw_cstr, OverlayAttr,file::MappedFile};
 use Fuseisk::cstr::buf::default;
 use Fuseisk::file::MutBytesExt;
 use Fuseisk::logging::setup_klog;
-use Fuseisk::result::{LibcReturn, LoggedResult, ResultExt};
+use Fuseisk::mountinfo::MountTable;
+use Fuseisk::MountStack;
+use Fuseisk::result::{LibcReturn, LoggedError, LoggedResult, ResultExt};
+use crate::block::{BlockDevices, DEV_DIR};
 use crate::bootconfig::BootConfig;
+use crate::bootmethod::{detect, BootMethod, FsProbe, RootDir};
+use crate::fstab::first_stage_fstab;
+use crate::fuse::mount_fuse_conf;
+use crate::rc::{InitRc, RcPatch};
 // use Fuseisk::{cstr, debug, info, logging, raw_cstr};
 
 unsafe extern "C" {
@@ -20,17 +28,39 @@ unsafe extern "C" {
 }
 
 const INIT_RC: &str = "/system/etc/init/hw/init.rc";
+// Android 9 legacy SAR keeps init.rc in the root of the system partition
+const LEGACY_INIT_RC: &str = "/init.rc";
+const SYSTEM_ROOT: &str = "/system_root";
+const OVERLAY_DIR: &str = "/overlay.d";
+// Where the patcher keeps the original init of the ramdisk
+pub const INIT_BACK: &str = "/init_back";
+// Marker appended to every init.rc we patch
+pub(crate) const INJECT_RC: &str = "#rzxrzfewfewfewf";
+// Persistent partitions that are writable before /data is decrypted, by preference
+const PREINIT_MOUNT_POINTS: &[&str] = &["/cache", "/metadata", "/persist", "/mnt/vendor/persist"];
+// Our directory on the preinit partition, and where it shows up. That has to
+// be outside /data, the real one is mounted over ours, so it goes on a tmpfs
+// of its own.
+const PREINIT_SUBDIR: &str = "fuseisk";
+const PREINIT_MIRROR: &str = "/data/.preinit";
+const PREINIT_TMPFS: &str = "/debug_ramdisk";
+pub const PREINIT_DIR: &str = "/debug_ramdisk/preinit";
 
 
 pub struct MagiskInit {
     preinit_dev: String,
-    mount_list: Vec<String>,
+    mounts: MountStack,
+    // /init is the original init again
+    init_restored: bool,
     argv: *mut *mut c_char,
     config: BootConfig,
     overlay_con: Vec<OverlayAttr>,
 }
 
-pub(crate) fn hexpatch_init_for_second_stage(writable: bool) {
+pub(crate) fn hexpatch_init_for_second_stage(
+    writable: bool,
+    mounts: &mut MountStack,
+) -> LoggedResult<()> {
     let init = if writable {
         MappedFile::open_rw(cstr!("/init"))
     } else {
@@ -39,7 +69,7 @@ pub(crate) fn hexpatch_init_for_second_stage(writable: bool) {
 
     let Ok(mut init) = init else {
         info!("Failed to open /init for hexpatch");
-        return;
+        return Err(LoggedError::default());
     };
 
     // Redirect original init to magiskinit
@@ -55,110 +85,190 @@ pub(crate) fn hexpatch_init_for_second_stage(writable: bool) {
         // If we cannot directly modify /init, we need to bind mount a replacement on top of it
         let src = cstr!("/init");
         let dest = cstr!("/data/init");
-        let _: LoggedResult<()> = (|| {
-            let mut fd = dest.create(O_CREAT | O_WRONLY, 0)?;
-            fd.write_all(init.as_ref())?;
+        let mut fd = dest.create(O_CREAT | O_WRONLY, 0)?;
+        fd.write_all(init.as_ref())?;
 
-            let attr = src.follow_link().get_attr()?;
-            dest.set_attr(&attr)?;
-            dest.bind_mount_to(src, false)?;
-            Ok(())
-        })();
+        let attr = src.follow_link().get_attr()?;
+        dest.set_attr(&attr)?;
+        dest.bind_mount_to(src, false)?;
+        mounts.push_kept("/init");
+    }
+    Ok(())
+}
+
+
+// Whether `/` is still the initramfs, which some 2SI devices never switch away from
+fn is_rootfs() -> bool {
+    const RAMFS_MAGIC: u32 = 0x858458f6;
+    const TMPFS_MAGIC: u32 = 0x01021994;
+    let mut st = MaybeUninit::<libc::statfs>::uninit();
+    unsafe {
+        if libc::statfs(raw_cstr!("/"), st.as_mut_ptr()) < 0 {
+            return false;
+        }
+        let f_type = st.assume_init().f_type as u32;
+        f_type == RAMFS_MAGIC || f_type == TMPFS_MAGIC
     }
 }
 
+// Lines of existing init scripts we rewrite, an empty replacement removes the line
+// or the section it starts
+const RC_REWRITES: &[(&str, &str)] = &[
+    // Do not let the stock recovery get reflashed over our patched image
+    ("service flash_recovery", "service flash_recovery /system/bin/true"),
+    // Samsung's persist.sys.zygote.early starts zygote before we get a chance to run
+    (
+        "on property:persist.sys.zygote.early=",
+        "on property:persist.sys.zygote.early.xxxxx=true",
+    ),
+];
+
+// Our own service, the scripts on the preinit partition run once /data is up
+fn default_rc() -> String {
+    format!(
+        "\
+service fuseisk_post_fs_data /system/bin/sh {preinit}/post-fs-data.sh
+    user root
+    seclabel u:r:su:s0
+    oneshot
+    disabled
+
+on post-fs-data
+    start fuseisk_post_fs_data
+",
+        preinit = PREINIT_DIR
+    )
+}
+
+pub(crate) fn default_rc_patch() -> RcPatch {
+    let mut patch = RcPatch::default();
+    for (from, to) in RC_REWRITES {
+        patch.add_rewrite(from, to);
+    }
+    patch.add_script(&default_rc());
+    patch
+}
 
+// The init.rc the original init reads, as seen once it runs
+pub(crate) fn find_init_rc(method: BootMethod, fs: &dyn FsProbe) -> Option<&'static str> {
+    let candidates: &[&str] = match method {
+        BootMethod::LegacySar => &[LEGACY_INIT_RC, INIT_RC],
+        _ => &[INIT_RC],
+    };
+    candidates.iter().copied().find(|path| fs.exists(path))
+}
+
+// Merge every rc script found in `dir` into the patch, returning their paths
+fn load_overlay_rc(dir: &str, patch: &mut RcPatch) -> Vec<PathBuf> {
+    let mut scripts: Vec<_> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "rc"))
+        .collect();
+    scripts.sort();
+    for path in &scripts {
+        if let Ok(script) = fs::read_to_string(path) {
+            debug!("Load rc script {}", path.display());
+            patch.add_script(&script);
+        }
+    }
+    scripts
+}
+
+// Apply the patch to the init.rc at `src` and write the result to `dest`
+pub(crate) fn patch_init_rc(src: &Utf8CStr, dest: &Utf8CStr, patch: &RcPatch) -> io::Result<()> {
+    debug!("Patching {} -> {}", src, dest);
+    let mut rc = InitRc::parse(&fs::read_to_string(src)?);
+    rc.apply(patch);
+    let mut file = OpenOptions::new().write(true).truncate(true).open(dest)?;
+    write!(file, "{}", rc)?;
+    writeln!(file, "{}", INJECT_RC)
+}
 
 impl MagiskInit {
     pub fn new(arg: *mut *mut c_char) -> Self {
         Self {
             preinit_dev: String::new(),
-            mount_list: Vec::new(),
+            mounts: MountStack::default(),
+            init_restored: false,
             overlay_con: Vec::new(),
             argv: arg,
-            config: BootConfig {
-                skip_initramfs: false,
-                force_normal_boot: false,
-                rootwait: false,
-                emulator: false,
-                slot: "".to_owned(),
-                dt_dir: "".to_owned(),
-                fstab_suffix: "".to_owned(),
-                hardware: "".to_owned(),
-                hardware_plat: "".to_owned(),
-                partition_map: Vec::new(),
-            },
+            config: BootConfig::default(),
         }
     }
     pub fn start(&mut self) -> LoggedResult<()> {
         if !cstr!("/proc/cmdline").exists() {
             cstr!("/proc").mkdir(0o755)?;
-            unsafe {
-                mount(
-                    raw_cstr!("proc"),
-                    raw_cstr!("/proc"),
-                    raw_cstr!("proc"),
-                    0,
-                    null(),
-                )
-            }.check_io_err()?;
-            self.mount_list.push("/proc".to_string());
+            cstr!("/proc").mount_fs(cstr!("proc"), cstr!("proc"), 0, None)?;
+            self.mounts.push("/proc");
         }
 
         if !cstr!("/sys/block").exists() {
             cstr!("/sys").mkdir(0o755)?;
-            unsafe {
-                mount(
-                    raw_cstr!("sysfs"),
-                    raw_cstr!("/sys"),
-                    raw_cstr!("sysfs"),
-                    0,
-                    null(),
-                )
-            }
-                .check_io_err()?;
-            self.mount_list.push("/sys".to_string());
+            cstr!("/sys").mount_fs(cstr!("sysfs"), cstr!("sysfs"), 0, None)?;
+            self.mounts.push("/sys");
         }
 
         setup_klog();
         self.config.init();
 
         let argv1 = unsafe { *self.argv.offset(1) };
-        if !argv1.is_null() && unsafe { CStr::from_ptr(argv1) == c"selinux_setup" } {
-                self.second_stage();
-            } else if self.config.skip_initramfs {
-                self.legacy_system_as_root();
-            } else if self.config.force_normal_boot {
-                self.first_stage();
-            } else if cstr!("/sbin/recovery").exists() || cstr!("/system/bin/recovery").exists() {
-                self.recovery();
-            } else if self.check_two_stage() {
-                self.first_stage();
-            } else {
-                self.rootfs();
+        let guard = self.mounts.guard();
+        // Re-executed by first stage init, not a boot method of its own
+        let res = if !argv1.is_null() && unsafe { CStr::from_ptr(argv1) == c"selinux_setup" } {
+            self.second_stage();
+            Ok(())
+        } else {
+            let detection = detect(&self.config, &RootDir::new("/"));
+            detection.print();
+            match detection.method {
+                BootMethod::LegacySar => self.legacy_system_as_root(),
+                BootMethod::TwoStage => self.first_stage(),
+                BootMethod::Recovery => self.recovery(),
+                BootMethod::RootFs => self.rootfs(),
+            }
+        };
+        match res {
+            Ok(()) => guard.commit(),
+            Err(_) => {
+                // Leave nothing of ours behind for the original init
+                info!("Boot failed, rolling back");
+                self.mounts.rollback(guard);
+                self.restore_ramdisk_init();
+            }
         }
 
         // // Finally execute the original init
         self.exec_init();
         Ok(())
     }
-    fn patch_ro_root(&mut self){
-        self.mount_list.push("/data".to_string());
-        if cstr!(INIT_RC).exists(){
-            debug!("file {} exists", INIT_RC);
-            cstr!(INIT_RC).copy_to(cstr!("/data/init.rc")).log_ok();
-            if cstr!("/data/init.rc").bind_mount_to(cstr!(INIT_RC), false).is_ok()
+    fn patch_ro_root(&mut self, method: BootMethod){
+        self.mounts.push("/data");
+        self.mount_preinit_dir().log_ok();
+        if let Some(init_rc) = find_init_rc(method, &RootDir::new("/")) {
+            debug!("file {} exists", init_rc);
+            let mut init_rc = init_rc.to_owned();
+            let init_rc = Utf8CStr::from_string(&mut init_rc);
+            init_rc.copy_to(cstr!("/data/init.rc")).log_ok();
+            // Our rc scripts can't be imported from /data, it is gone once init runs
+            let mut patch = default_rc_patch();
+            load_overlay_rc("/data/overlay.d", &mut patch);
+            patch_init_rc(init_rc, cstr!("/data/init.rc"), &patch).log_ok();
+            if cstr!("/data/init.rc").bind_mount_to(init_rc, false).is_ok()
             {
-                debug!("Bind mount /data/init.rc -> {}",INIT_RC);
-                let mut file = OpenOptions::new().append(true).open(INIT_RC).unwrap();
-                writeln!(file, "{}", "#rzxrzfewfewfewf");
+                self.mounts.push_kept(init_rc);
+                debug!("Bind mount /data/init.rc -> {}",init_rc);
             } else {
-                debug!("Bind mount /data/init.rc -> {} failed",INIT_RC);
+                debug!("Bind mount /data/init.rc -> {} failed",init_rc);
             }
             // mou
         }else {
             debug!("file {} is not exists", INIT_RC);
         }
+        // FUSE views of system files asked for by overlay.d
+        mount_fuse_conf("/data/overlay.d", &mut self.mounts);
         // let result =  unsafe { libc::fork() };
         // if result < 0{
         //     debug!("Fork failed");
@@ -189,42 +299,206 @@ impl MagiskInit {
             *self.argv = raw_cstr!("/system/bin/init") as *mut _;
         }
 
-        /*
-        Some weird devices like meizu, uses 2SI but still have legacy rootfs
+        // Some weird devices like meizu, uses 2SI but still have legacy rootfs
         if is_rootfs() {
-        // We are still on rootfs, so make sure we will execute the init of the 2nd stage
-        let init_path = cstr!("/init");
-        init_path.remove().ok();
-        init_path
-        .create_symlink_to(cstr!("/system/bin/init"))
-        .log_ok();
+            // We are still on rootfs, so make sure we will execute the init of the 2nd stage
+            let init_path = cstr!("/init");
+            init_path.remove().ok();
+            init_path
+                .create_symlink_to(cstr!("/system/bin/init"))
+                .log_ok();
+            self.patch_rw_root();
+        } else {
+            self.patch_ro_root(BootMethod::TwoStage);
+        }
+    }
+    fn legacy_system_as_root(&mut self) -> LoggedResult<()> {
+        info!("Legacy SAR Init");
+        self.prepare_data();
+        if self.mount_system_root()? {
+            hexpatch_init_for_second_stage(false, &mut self.mounts)?;
+        } else {
+            self.patch_ro_root(BootMethod::LegacySar);
+        }
+        Ok(())
+    }
+    // Partition names the system partition may go by, most specific first
+    fn system_partnames(&self) -> Vec<String> {
+        // Early mount devices describe system in the device tree fstab,
+        // otherwise the ramdisk fstab may list it
+        let mut names: Vec<String> = first_stage_fstab("", &self.config)
+            .iter()
+            .filter(|e| e.mount_point == "/" || e.mount_point == "/system")
+            .map(|e| e.partname().to_owned())
+            .collect();
+
+        // Legacy SAR dm-verity
+        names.push("vroot".to_owned());
+        // NVIDIA naming scheme
+        names.push("APP".to_owned());
+        names.push(format!("system{}", self.config.slot));
+        names
+    }
+    fn mount_system_root(&mut self) -> LoggedResult<bool> {
+        debug!("Mounting system_root");
+
+        // There's no /dev in stub cpio
+        cstr!("/dev").mkdir(0o755)?;
+        let block_dev = cstr!("/dev/root");
+        let names = self.system_partnames();
+        let mut blocks = BlockDevices::new(&self.config);
+        loop {
+            if names.iter().any(|name| blocks.setup_at(name, block_dev)) {
+                break;
+            }
+            // Poll forever if rootwait was given in cmdline
+            if !self.config.rootwait {
+                info!("Cannot find root partition, abort");
+                return Err(LoggedError::default());
+            }
+        }
+
+        cstr!(SYSTEM_ROOT).mkdir(0o755)?;
+        let mounted = [cstr!("ext4"), cstr!("erofs")].iter().any(|fstype| {
+            cstr!(SYSTEM_ROOT)
+                .mount_fs(fstype, block_dev, MS_RDONLY, None)
+                .is_ok()
+        });
+        if !mounted {
+            info!("Cannot mount root partition, abort");
+            return Err(LoggedError::default());
+        }
+        self.mounts.push_kept(SYSTEM_ROOT);
+
+        self.switch_root(cstr!(SYSTEM_ROOT))?;
+
+        // Make dev writable
+        cstr!("/dev").mount_fs(cstr!("tmpfs"), cstr!("tmpfs"), 0, Some(cstr!("mode=755")))?;
+        self.mounts.push("/dev");
+
+        // Use the apex folder to determine whether 2SI (Android 10+)
+        let is_two_stage = cstr!("/apex").exists();
+        debug!("is_two_stage: [{}]", is_two_stage);
+
+        // API 28 AVD uses a legacy SAR setup that does not mount vendor early
+        if !is_two_stage && self.config.emulator {
+            cstr!("/dev/block").mkdir(0o755)?;
+            if blocks.setup_at("vendor", cstr!("/dev/block/vde1"))
+                && cstr!("/vendor")
+                    .mount_fs(cstr!("ext4"), cstr!("/dev/block/vde1"), MS_RDONLY, None)
+                    .log()
+                    .is_ok()
+            {
+                self.mounts.push_kept("/vendor");
+            }
+        }
+
+        Ok(is_two_stage)
+    }
+    fn switch_root(&self, path: &Utf8CStr) -> LoggedResult<()> {
+        debug!("Switch root to {}", path);
+
+        // Carry every mount (proc, sys, and the /data tmpfs holding our payload) over
+        let table = MountTable::load()?;
+        let mut moved: Vec<&str> = Vec::new();
+        for target in table.mounts().iter().map(|m| m.target.as_str()) {
+            if target == "/"
+                || target == path.as_str()
+                || moved.iter().any(|m| {
+                    *m == target || target.strip_prefix(m).is_some_and(|s| s.starts_with('/'))
+                })
+            {
+                continue;
+            }
+            moved.push(target);
+            let mut src = target.to_owned();
+            let mut dest = format!("{}{}", path, target);
+            let src = Utf8CStr::from_string(&mut src);
+            let dest = Utf8CStr::from_string(&mut dest);
+            dest.mkdir(0o755).log_ok();
+            src.move_mount_to(dest).log_ok();
+        }
+
+        unsafe { libc::chdir(path.as_ptr()) }.check_os_err("chdir", Some(path), None)?;
+        path.move_mount_to(cstr!("/"))?;
+        unsafe { libc::chroot(raw_cstr!(".")) }.check_os_err("chroot", Some(path), None)?;
+        Ok(())
+    }
+    fn recovery(&mut self) -> LoggedResult<()> {
+        // On A-only devices the recovery ramdisk also boots the system, the
+        // bootloader tells us through androidboot.mode which one it wants.
+        // Without that hint, trust the ramdisk and assume recovery.
+        if self.config.mode.is_empty() || self.config.mode == "recovery" {
+            info!("Ramdisk is recovery, abort");
+            self.restore_ramdisk_init();
+            cstr!(OVERLAY_DIR).remove_all().ok();
+            Ok(())
+        } else {
+            info!("Recovery ramdisk in [{}] mode", self.config.mode);
+            self.rootfs()
+        }
+    }
+    fn rootfs(&mut self) -> LoggedResult<()> {
+        info!("RootFS Init");
+        self.restore_ramdisk_init();
         self.patch_rw_root();
+        Ok(())
+    }
+    fn patch_rw_root(&mut self) {
+        self.mount_preinit_dir().log_ok();
+
+        // The same patch as on a read-only root. The rc scripts of overlay.d are
+        // merged into init.rc, so they must not replace a vendor /init.*.rc of
+        // the same name when the payload is moved into place.
+        let mut patch = default_rc_patch();
+        for script in load_overlay_rc(OVERLAY_DIR, &mut patch) {
+            fs::remove_file(script).log_ok();
+        }
+        if let Some(conf) = mount_fuse_conf(OVERLAY_DIR, &mut self.mounts) {
+            fs::remove_file(conf).log_ok();
+        }
+
+        // Drop the rest of our payload into place
+        if cstr!(OVERLAY_DIR).exists() {
+            debug!("Move {} -> /", OVERLAY_DIR);
+            cstr!(OVERLAY_DIR).move_to(cstr!("/")).log_ok();
+            cstr!(OVERLAY_DIR).remove_all().ok();
+        }
+
+        // The rootfs is writable, so patch /init.rc in place
+        let init_rc = cstr!(LEGACY_INIT_RC);
+        if init_rc.exists() {
+            patch_init_rc(init_rc, init_rc, &patch).log_ok();
+        } else if cstr!(INIT_RC).exists() {
+            // Newer Android reads init.rc from the read-only system partition
+            self.patch_ro_root(BootMethod::RootFs);
         } else {
-        */
-        self.patch_ro_root();
-        // }
+            debug!("file {} is not exists", init_rc);
+        }
     }
-    fn legacy_system_as_root(&mut self) {}
-    fn recovery(&mut self) {}
-    fn rootfs(&mut self) {}
-    fn first_stage(&self) {
+    fn first_stage(&mut self) -> LoggedResult<()> {
         info!("First Stage Init");
         self.prepare_data();
 
         if !cstr!("/sdcard").exists() && !cstr!("/first_stage_ramdisk/sdcard").exists() {
-            self.hijack_init_with_switch_root();
+            hijack_init_with_switch_root("", self.config.force_normal_boot, &mut self.mounts)?;
             self.restore_ramdisk_init();
         } else {
             info!("First Stage start error, /sdcard or /first_stage_ramdisk/sdcard is exits");
             self.restore_ramdisk_init();
             // Fallback to hexpatch if /sdcard exists
-            hexpatch_init_for_second_stage(true);
+            hexpatch_init_for_second_stage(true, &mut self.mounts)?;
         }
+        Ok(())
     }
-    fn restore_ramdisk_init(&self) {
+    fn restore_ramdisk_init(&mut self) {
+        if self.init_restored {
+            return;
+        }
+        self.init_restored = true;
         cstr!("/init").remove().ok();
 
-        let orig_init = cstr!("init_back");
+        let orig_init = cstr!(INIT_BACK);
 
         if orig_init.exists() {
             orig_init.rename_to(cstr!("/init")).log_ok();
@@ -237,16 +511,8 @@ impl MagiskInit {
                 .log_ok();
         }
     }
-    fn check_two_stage(&self) -> bool {
-        return true;
-    }
     pub(crate) fn exec_init(&mut self) {
-        for path in self.mount_list.iter_mut().rev() {
-            let path = Utf8CStr::from_string(path);
-            if path.unmount().log().is_ok() {
-                debug!("Unmount [{}]", path);
-            }
-        }
+        self.mounts.unmount_temporary();
         unsafe {
             execve(raw_cstr!("/init"), self.argv.cast(), environ.cast())
                 .check_io_err()
@@ -255,84 +521,203 @@ impl MagiskInit {
         }
     }
 
-    pub(crate) fn prepare_data(&self) {
+    // The first ext4/f2fs partition of the fstab at a preinit mount point
+    fn find_preinit_dev(&self) -> Option<String> {
+        let fstab = first_stage_fstab("", &self.config);
+        PREINIT_MOUNT_POINTS.iter().find_map(|mount_point| {
+            fstab
+                .iter()
+                .filter(|e| e.mount_point == *mount_point)
+                .find(|e| e.fs_type == "ext4" || e.fs_type == "f2fs")
+                .map(|e| e.partname().to_owned())
+        })
+    }
+    // Expose our directory on the preinit partition at PREINIT_DIR, so
+    // modules and sepolicy rules survive reboots without decrypting /data
+    fn mount_preinit_dir(&mut self) -> LoggedResult<()> {
+        if self.preinit_dev.is_empty() {
+            let Some(dev) = self.find_preinit_dev() else {
+                info!("No preinit partition found");
+                return Err(LoggedError::default());
+            };
+            self.preinit_dev = dev;
+        }
+        info!("preinit_dev=[{}]", self.preinit_dev);
+
+        let mirror = cstr!(PREINIT_MIRROR);
+        mirror.mkdirs(0o700)?;
+        // First stage init may have mounted it already
+        let table = MountTable::load()?;
+
+        let mut blocks = BlockDevices::new(&self.config);
+        let mut dev = match blocks.setup(&self.preinit_dev) {
+            Ok(dev) => dev,
+            Err(e) => {
+                cstr!(DEV_DIR).remove_all().ok();
+                return Err(e);
+            }
+        };
+        let dev = Utf8CStr::from_string(&mut dev);
+        let mounted = match blocks
+            .find(&self.preinit_dev)
+            .and_then(|b| table.find_by_dev(b.major, b.minor).first().copied())
+        {
+            Some(m) => Utf8CStr::from_string(&mut m.target.clone())
+                .bind_mount_to(mirror, false)
+                .log()
+                .is_ok(),
+            None => [cstr!("ext4"), cstr!("f2fs")]
+                .iter()
+                .any(|fstype| mirror.mount_fs(fstype, dev, 0, None).is_ok()),
+        };
+        // On the second stage this is the real /dev, don't leave our nodes there
+        cstr!(DEV_DIR).remove_all().ok();
+        if !mounted {
+            info!("Cannot mount preinit [{}]", self.preinit_dev);
+            mirror.remove().ok();
+            return Err(LoggedError::default());
+        }
+
+        let mut subdir = format!("{}/{}", PREINIT_MIRROR, PREINIT_SUBDIR);
+        let subdir = Utf8CStr::from_string(&mut subdir);
+        let guard = self.mounts.guard();
+        let res: LoggedResult<()> = (|| {
+            subdir.mkdirs(0o700)?;
+            let tmpfs = cstr!(PREINIT_TMPFS);
+            tmpfs.mkdirs(0o755)?;
+            tmpfs.mount_fs(cstr!("tmpfs"), cstr!("tmpfs"), 0, Some(cstr!("mode=755")))?;
+            self.mounts.push_kept(PREINIT_TMPFS);
+            cstr!(PREINIT_DIR).mkdir(0o700)?;
+            subdir.bind_mount_to(cstr!(PREINIT_DIR), false)?;
+            self.mounts.push_kept(PREINIT_DIR);
+            Ok(())
+        })();
+        mirror.unmount().log_ok();
+        mirror.remove().ok();
+        if res.is_err() {
+            self.mounts.rollback(guard);
+            return res;
+        }
+        guard.commit();
+        debug!("Bind mount {} -> {}", subdir, PREINIT_DIR);
+        Ok(())
+    }
+    pub(crate) fn prepare_data(&mut self) {
         debug!("Setup data tmp");
         cstr!("/data").mkdir(0o755).log_ok();
-        unsafe {
-            mount(
-                raw_cstr!("magisk"),
-                raw_cstr!("/data"),
-                raw_cstr!("tmpfs"),
-                0,
-                raw_cstr!("mode=755").cast(),
-            )
+        if cstr!("/data")
+            .mount_fs(cstr!("tmpfs"), cstr!("magisk"), 0, Some(cstr!("mode=755")))
+            .log()
+            .is_ok()
+        {
+            // Second stage init runs from it
+            self.mounts.push_kept("/data");
         }
-            .check_io_err()
-            .log_ok();
 
         cstr!("/init").copy_to(cstr!("/data/magiskinit")).log_ok();
         // cstr!("/.backup").copy_to(cstr!("/data/.backup")).log_ok();
-        // cstr!("/overlay.d")
-        //     .copy_to(cstr!("/data/overlay.d"))
-        //     .log_ok();
+        if cstr!(OVERLAY_DIR).exists() {
+            cstr!(OVERLAY_DIR)
+                .copy_to(cstr!("/data/overlay.d"))
+                .log_ok();
+        }
     }
-    pub(crate) fn hijack_init_with_switch_root(&self) {
-        // We make use of original init's `SwitchRoot` to help us bind mount
-        // magiskinit to /system/bin/init to hijack second stage init.
-        //
-        // Two important assumption about 2SI:
-        // - The second stage init is always /system/bin/init
-        // - After `SwitchRoot`, /sdcard is always a symlink to `/storage/self/primary`.
-        //
-        // `SwitchRoot` will perform the following:
-        // - Recursive move all mounts under `/` to `/system`
-        // - chroot to `/system`
-        //
-        // The trick here is that in Magisk's first stage init, we can mount magiskinit to /sdcard,
-        // and create a symlink at /storage/self/primary pointing to /system/system/bin/init.
-        //
-        // During init's `SwitchRoot`, it will mount move /sdcard (which is magiskinit)
-        // to /system/sdcard, which is a symlink to /storage/self/primary, which is a
-        // symlink to /system/system/bin/init, which will eventually become /system/bin/init after
-        // chroot to /system. The effective result is that we coerce the original init into bind
-        // mounting magiskinit to /system/bin/init, successfully hijacking the second stage init.
-        //
-        // An edge case is that some devices (like meizu) use 2SI but does not switch root.
-        // In that case, they must already have a /sdcard in ramfs, thus we can check if
-        // /sdcard exists and fallback to using hexpatch.
+}
 
-        if self.config.force_normal_boot {
-            cstr!("/first_stage_ramdisk/storage/self")
-                .mkdirs(0o755)
-                .log_ok();
-            cstr!("/first_stage_ramdisk/storage/self/primary")
-                .create_symlink_to(cstr!("/system/system/bin/init"))
-                .log_ok();
-            debug!("Symlink /first_stage_ramdisk/storage/self/primary -> /system/system/bin/init");
-            cstr!("/first_stage_ramdisk/sdcard")
-                .create(O_RDONLY | O_CREAT | O_CLOEXEC, 0)
-                .log_ok();
-        } else {
-            cstr!("/storage/self").mkdirs(0o755).log_ok();
-            cstr!("/storage/self/primary")
-                .create_symlink_to(cstr!("/system/system/bin/init"))
-                .log_ok();
-            debug!("Symlink /storage/self/primary -> /system/system/bin/init");
+// mkdirs() that records every directory it creates
+fn mkdirs_tracked(path: &str, mounts: &mut MountStack) -> LoggedResult<()> {
+    let mut dir = String::new();
+    for name in path.split('/').filter(|s| !s.is_empty()) {
+        dir.push('/');
+        dir.push_str(name);
+        let path = Utf8CStr::from_string(&mut dir);
+        if !path.exists() {
+            path.mkdir(0o755)?;
+            mounts.push_created(path);
         }
-        cstr!("/init").rename_to(cstr!("/sdcard")).log_ok();
+    }
+    Ok(())
+}
+
+// The ramdisk changes are recorded in `mounts` next to the mount. If the
+// hijack fails, a rollback puts /init back and removes the symlink, which would
+// otherwise leave the original init in a bootloop.
+pub(crate) fn hijack_init_with_switch_root(
+    root: &str,
+    force_normal_boot: bool,
+    mounts: &mut MountStack,
+) -> LoggedResult<()> {
+    // We make use of original init's `SwitchRoot` to help us bind mount
+    // magiskinit to /system/bin/init to hijack second stage init.
+    //
+    // Two important assumption about 2SI:
+    // - The second stage init is always /system/bin/init
+    // - After `SwitchRoot`, /sdcard is always a symlink to `/storage/self/primary`.
+    //
+    // `SwitchRoot` will perform the following:
+    // - Recursive move all mounts under `/` to `/system`
+    // - chroot to `/system`
+    //
+    // The trick here is that in Magisk's first stage init, we can mount magiskinit to /sdcard,
+    // and create a symlink at /storage/self/primary pointing to /system/system/bin/init.
+    //
+    // During init's `SwitchRoot`, it will mount move /sdcard (which is magiskinit)
+    // to /system/sdcard, which is a symlink to /storage/self/primary, which is a
+    // symlink to /system/system/bin/init, which will eventually become /system/bin/init after
+    // chroot to /system. The effective result is that we coerce the original init into bind
+    // mounting magiskinit to /system/bin/init, successfully hijacking the second stage init.
+    //
+    // An edge case is that some devices (like meizu) use 2SI but does not switch root.
+    // In that case, they must already have a /sdcard in ramfs, thus we can check if
+    // /sdcard exists and fallback to using hexpatch.
 
-        // First try to mount magiskinit from rootfs to workaround Samsung RKP
-        if cstr!("/sdcard")
-            .bind_mount_to(cstr!("/sdcard"), false)
+    let base = if force_normal_boot {
+        format!("{}/first_stage_ramdisk", root)
+    } else {
+        root.to_owned()
+    };
+    mkdirs_tracked(&format!("{}/storage/self", base), mounts).log_ok();
+    let mut primary = format!("{}/storage/self/primary", base);
+    let primary = Utf8CStr::from_string(&mut primary);
+    if primary
+        .create_symlink_to(cstr!("/system/system/bin/init"))
+        .log()
+        .is_ok()
+    {
+        mounts.push_created(primary);
+        debug!("Symlink {} -> /system/system/bin/init", primary);
+    }
+    if force_normal_boot {
+        let mut sdcard = format!("{}/sdcard", base);
+        let sdcard = Utf8CStr::from_string(&mut sdcard);
+        if sdcard
+            .create(O_RDONLY | O_CREAT | O_CLOEXEC, 0)
+            .log()
             .is_ok()
         {
-            debug!("Bind mount /sdcard -> /sdcard");
-        } else {
-            // Binding mounting from rootfs is not supported before Linux 3.12
-            cstr!("/data/magiskinit")
-                .bind_mount_to(cstr!("/sdcard"), false)
-                .log_ok();
-            debug!("Bind mount /data/magiskinit -> /sdcard");
+            mounts.push_created(sdcard);
         }
     }
+
+    let mut init = format!("{}/init", root);
+    let init = Utf8CStr::from_string(&mut init);
+    let mut sdcard = format!("{}/sdcard", root);
+    let sdcard = Utf8CStr::from_string(&mut sdcard);
+    init.rename_to(sdcard)?;
+    mounts.push_rename(init, sdcard);
+
+    // First try to mount magiskinit from rootfs to workaround Samsung RKP.
+    // With the new mount API the bind mount is built detached, and only
+    // shows up in the mount table once it is in place.
+    if sdcard.clone_mount_to(sdcard, false).is_ok() {
+        debug!("Bind mount /sdcard -> /sdcard");
+    } else {
+        // Binding mounting from rootfs is not supported before Linux 3.12
+        let mut magiskinit = format!("{}/data/magiskinit", root);
+        let magiskinit = Utf8CStr::from_string(&mut magiskinit);
+        magiskinit.clone_mount_to(sdcard, false)?;
+        debug!("Bind mount /data/magiskinit -> /sdcard");
+    }
+    mounts.push_kept(sdcard);
+    Ok(())
 }
@@ -1,12 +1,18 @@
+pub mod archive;
+pub mod compress;
 pub mod cstr;
 mod dir;
 pub mod file;
+pub mod fusedev;
 pub mod logging;
 mod mount;
+pub mod mountinfo;
 pub mod result;
 
 
 
+pub use mount::{MountGuard, MountStack};
+
 use std::{fs, ptr};
 use crate::cstr::Utf8CString;
 
@@ -7,7 +7,7 @@ use libc::{
     STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO, S_IFCHR,
 };
 use libc::{sleep, O_RDWR};
-use std::io::stdout;
+use std::io::{stderr, stdout};
 use std::mem::ManuallyDrop;
 use std::process::exit;
 use std::{
@@ -152,6 +152,22 @@ pub fn setup_klog() {
     }
 }
 
+// For the commands run from a shell instead of as init
+pub fn setup_stderr_log() {
+    fn stderr_log_write(_: LogLevel, msg: &Utf8CStr) {
+        let mut err = stderr();
+        let _ = err.write_all(msg.as_bytes()).ok();
+    }
+
+    let logger = Logger {
+        write: stderr_log_write,
+        flags: 0,
+    };
+    unsafe {
+        LOGGER = logger;
+    }
+}
+
 pub fn set_log_level_state(level: LogLevel, enabled: bool) {
     let flag = level.as_disable_flag();
     unsafe {
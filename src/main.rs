@@ -1,15 +1,22 @@
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 
 mod init;
+mod block;
 mod bootconfig;
+mod bootmethod;
+mod fstab;
+mod fuse;
+mod patch;
+mod rc;
 
 mod test;
 
-use std::ffi::c_char;
+use std::ffi::{c_char, CStr};
 use Fuseisk::result::ResultExt;
 use crate::init::MagiskInit;
 // use Fuseisk::{ MagiskLib::MagiskInit};
 
+#[cfg(not(test))]
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn main(
     argc: i32,
@@ -17,11 +24,15 @@ pub unsafe extern "C" fn main(
     _envp: *const *const c_char,
 ) -> i32 {
     unsafe {
-        // umask(0);
-        libc::umask(0);
         if libc::getpid() == 1 {
+            // umask(0);
+            libc::umask(0);
             MagiskInit::new(argv).start();
+            return 0;
         }
-        return 0;
+        let args: Vec<String> = (0..argc as usize)
+            .map(|i| CStr::from_ptr(*argv.add(i)).to_string_lossy().into_owned())
+            .collect();
+        patch::main(&args)
     }
 }
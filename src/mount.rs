@@ -1,6 +1,39 @@
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
 use std::ptr;
+use libc::{c_uint, c_ulong, AT_FDCWD, AT_RECURSIVE, ENOSYS, OPEN_TREE_CLOEXEC, OPEN_TREE_CLONE};
 use crate::cstr::Utf8CStr;
-use crate::result::{LibcReturn, OsResult};
+use crate::mountinfo::MountTable;
+use crate::result::{LibcReturn, OsError, OsResult, ResultExt};
+use crate::{cstr, debug};
+
+// The new mount API of Linux 5.2, libc does not define all of it for Android
+const MOVE_MOUNT_F_EMPTY_PATH: c_uint = 0x04;
+
+// Kernels without the new mount API, or seccomp filters hiding it
+fn is_unsupported(e: &OsError) -> bool {
+    e.code() == ENOSYS
+}
+
+// A mount that is not attached anywhere yet, invisible until moved into place
+pub struct DetachedMount {
+    fd: OwnedFd,
+}
+
+impl DetachedMount {
+    pub fn move_mount_to<'a>(&self, path: &'a Utf8CStr) -> OsResult<'a, ()> {
+        unsafe {
+            libc::syscall(
+                libc::SYS_move_mount,
+                self.fd.as_raw_fd(),
+                cstr!("").as_ptr(),
+                AT_FDCWD,
+                path.as_ptr(),
+                MOVE_MOUNT_F_EMPTY_PATH,
+            )
+            .check_os_err("move_mount", Some(path), None)
+        }
+    }
+}
 
 impl Utf8CStr {
 
@@ -23,4 +56,276 @@ impl Utf8CStr {
             libc::umount2(self.as_ptr(), libc::MNT_DETACH).check_os_err("unmount", Some(self), None)
         }
     }
+
+    // Mount `source` of type `fstype` here, `data` being the filesystem options
+    pub fn mount_fs<'a>(
+        &'a self,
+        fstype: &Utf8CStr,
+        source: &'a Utf8CStr,
+        flags: c_ulong,
+        data: Option<&Utf8CStr>,
+    ) -> OsResult<'a, ()> {
+        unsafe {
+            libc::mount(
+                source.as_ptr(),
+                self.as_ptr(),
+                fstype.as_ptr(),
+                flags,
+                data.map_or(ptr::null(), |d| d.as_ptr().cast()),
+            )
+            .check_os_err("mount", Some(source), Some(self))
+        }
+    }
+
+    pub fn move_mount_to<'a>(&'a self, path: &'a Utf8CStr) -> OsResult<'a, ()> {
+        unsafe {
+            libc::mount(
+                self.as_ptr(),
+                path.as_ptr(),
+                ptr::null(),
+                libc::MS_MOVE,
+                ptr::null(),
+            )
+            .check_os_err("move_mount", Some(self), Some(path))
+        }
+    }
+
+    // A detached copy of the mount tree here, the same as a bind mount once moved
+    pub fn open_tree(&self, rec: bool) -> OsResult<DetachedMount> {
+        let rec = if rec { AT_RECURSIVE as c_uint } else { 0 };
+        unsafe {
+            let fd = libc::syscall(
+                libc::SYS_open_tree,
+                AT_FDCWD,
+                self.as_ptr(),
+                OPEN_TREE_CLONE | OPEN_TREE_CLOEXEC | rec,
+            )
+            .as_os_result("open_tree", Some(self), None)?;
+            Ok(DetachedMount {
+                fd: OwnedFd::from_raw_fd(fd as i32),
+            })
+        }
+    }
+
+    // bind_mount_to() through a detached mount when the kernel supports it
+    pub fn clone_mount_to<'a>(&'a self, path: &'a Utf8CStr, rec: bool) -> OsResult<'a, ()> {
+        match self.open_tree(rec) {
+            Ok(mnt) => mnt.move_mount_to(path),
+            Err(e) if is_unsupported(&e) => self.bind_mount_to(path, rec),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Only changes the flags of this mount, not of the filesystem
+    fn remount(&self, flags: c_ulong) -> OsResult<()> {
+        unsafe {
+            libc::mount(
+                ptr::null(),
+                self.as_ptr(),
+                ptr::null(),
+                libc::MS_REMOUNT | libc::MS_BIND | flags,
+                ptr::null(),
+            )
+            .check_os_err("remount", Some(self), None)
+        }
+    }
+
+    pub fn remount_ro(&self) -> OsResult<()> {
+        self.remount(libc::MS_RDONLY)
+    }
+
+    pub fn remount_rw(&self) -> OsResult<()> {
+        self.remount(0)
+    }
+
+    fn set_propagation(&self, flag: c_ulong, rec: bool) -> OsResult<()> {
+        let rec = if rec { libc::MS_REC } else { 0 };
+        unsafe {
+            libc::mount(
+                ptr::null(),
+                self.as_ptr(),
+                ptr::null(),
+                flag | rec,
+                ptr::null(),
+            )
+            .check_os_err("set_propagation", Some(self), None)
+        }
+    }
+
+    pub fn make_private(&self, rec: bool) -> OsResult<()> {
+        self.set_propagation(libc::MS_PRIVATE, rec)
+    }
+
+    pub fn make_slave(&self, rec: bool) -> OsResult<()> {
+        self.set_propagation(libc::MS_SLAVE, rec)
+    }
+
+    pub fn make_shared(&self, rec: bool) -> OsResult<()> {
+        self.set_propagation(libc::MS_SHARED, rec)
+    }
+
+    // Read-only without upper and work, `lower` may list several directories split by ':'
+    pub fn mount_overlay<'a>(
+        &'a self,
+        lower: &'a Utf8CStr,
+        upper: Option<&Utf8CStr>,
+        work: Option<&Utf8CStr>,
+    ) -> OsResult<'a, ()> {
+        let mut data = format!("lowerdir={}", lower);
+        if let Some(upper) = upper {
+            data.push_str(",upperdir=");
+            data.push_str(upper);
+        }
+        if let Some(work) = work {
+            data.push_str(",workdir=");
+            data.push_str(work);
+        }
+        self.mount_fs(
+            cstr!("overlay"),
+            lower,
+            0,
+            Some(Utf8CStr::from_string(&mut data)),
+        )
+    }
+}
+
+enum Change {
+    Mount {
+        target: String,
+        // Still there when the original init runs
+        keep: bool,
+    },
+    // Files of the ramdisk we moved or created for the original init, only
+    // undone by a rollback
+    Rename {
+        from: String,
+        to: String,
+    },
+    Create(String),
+}
+
+impl Change {
+    fn is_temporary(&self) -> bool {
+        matches!(self, Change::Mount { keep: false, .. })
+    }
+}
+
+// Every mount we make, in order, so they can be undone in reverse.
+//
+// Temporary mounts (proc, sys, our tmpfs) go away before the original init
+// runs, kept ones (the init hijack, patched rc files) are made for it. The
+// ramdisk files that go with the kept mounts are tracked alongside them.
+#[derive(Default)]
+pub struct MountStack {
+    changes: Vec<Change>,
+}
+
+// The mounts pushed to a MountStack after the guard was taken, as a group
+#[must_use]
+pub struct MountGuard {
+    depth: usize,
+}
+
+impl MountGuard {
+    // Keep the group, it is handled like the rest of the stack from now on
+    pub fn commit(self) {}
+}
+
+impl MountStack {
+    pub fn push(&mut self, target: &str) {
+        self.changes.push(Change::Mount {
+            target: target.to_owned(),
+            keep: false,
+        });
+    }
+
+    pub fn push_kept(&mut self, target: &str) {
+        self.changes.push(Change::Mount {
+            target: target.to_owned(),
+            keep: true,
+        });
+    }
+
+    // `from` was renamed to `to`
+    pub fn push_rename(&mut self, from: &str, to: &str) {
+        self.changes.push(Change::Rename {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        });
+    }
+
+    // A file, symlink or directory that did not exist before
+    pub fn push_created(&mut self, path: &str) {
+        self.changes.push(Change::Create(path.to_owned()));
+    }
+
+    pub fn guard(&self) -> MountGuard {
+        MountGuard {
+            depth: self.changes.len(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    // The targets of the mounts still tracked, in order
+    pub fn mounts(&self) -> Vec<&str> {
+        self.changes
+            .iter()
+            .filter_map(|c| match c {
+                Change::Mount { target, .. } => Some(target.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn undo(changes: impl DoubleEndedIterator<Item = Change>) {
+        let table = MountTable::load().log().unwrap_or_default();
+        for change in changes.rev() {
+            match change {
+                Change::Mount { mut target, .. } => {
+                    // Moved away (by switch_root) or already unmounted
+                    if !table.is_mounted(&target) {
+                        debug!("Not mounted [{}]", target);
+                        continue;
+                    }
+                    let target = Utf8CStr::from_string(&mut target);
+                    if target.unmount().log().is_ok() {
+                        debug!("Unmount [{}]", target);
+                    }
+                }
+                Change::Rename { mut from, mut to } => {
+                    let from = Utf8CStr::from_string(&mut from);
+                    let to = Utf8CStr::from_string(&mut to);
+                    if to.rename_to(from).log().is_ok() {
+                        debug!("Move back [{}] -> [{}]", to, from);
+                    }
+                }
+                Change::Create(mut path) => {
+                    let path = Utf8CStr::from_string(&mut path);
+                    if path.remove().log().is_ok() {
+                        debug!("Remove [{}]", path);
+                    }
+                }
+            }
+        }
+    }
+
+    // Undo everything of the group, kept or not
+    pub fn rollback(&mut self, guard: MountGuard) {
+        let depth = guard.depth.min(self.changes.len());
+        MountStack::undo(self.changes.drain(depth..));
+    }
+
+    // Undo the temporary mounts, before handing over to the original init
+    pub fn unmount_temporary(&mut self) {
+        let (temporary, kept) = self.changes.drain(..).partition(Change::is_temporary);
+        self.changes = kept;
+        MountStack::undo(temporary.into_iter());
+    }
 }
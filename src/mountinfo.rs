@@ -0,0 +1,152 @@
+use std::fs;
+use std::io;
+
+// The mount table of a process, see proc_pid_mountinfo(5)
+//
+//   36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+//
+// Optional fields come before the lone "-", paths have space, tab, newline and
+// backslash escaped as octal.
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfo {
+    pub id: u32,
+    pub parent: u32,
+    pub major: u32,
+    pub minor: u32,
+    // The directory of the filesystem that is mounted, "/" unless a bind mount
+    pub root: String,
+    pub target: String,
+    // Per mount options, like ro or nosuid
+    pub options: String,
+    // Propagation, like shared:1 or master:2
+    pub optional: Vec<String>,
+    pub fs_type: String,
+    pub source: String,
+    // Per superblock options
+    pub super_options: String,
+}
+
+fn unescape(field: &str) -> String {
+    if !field.contains('\\') {
+        return field.to_owned();
+    }
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .filter(|d| d.iter().all(|c| (b'0'..=b'7').contains(c)))
+            .and_then(|d| u8::from_str_radix(std::str::from_utf8(d).ok()?, 8).ok());
+        match (bytes[i], octal) {
+            (b'\\', Some(c)) => {
+                out.push(c);
+                i += 4;
+            }
+            (c, _) => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// `path` itself or anything below it
+fn is_under(path: &str, dir: &str) -> bool {
+    let dir = dir.trim_end_matches('/');
+    match path.strip_prefix(dir) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+impl MountInfo {
+    pub fn parse(line: &str) -> Option<MountInfo> {
+        let mut fields = line.split(' ');
+        let id = fields.next()?.parse().ok()?;
+        let parent = fields.next()?.parse().ok()?;
+        let (major, minor) = fields.next()?.split_once(':')?;
+        let root = unescape(fields.next()?);
+        let target = unescape(fields.next()?);
+        let options = fields.next()?.to_owned();
+        let mut optional = Vec::new();
+        loop {
+            match fields.next()? {
+                "-" => break,
+                field => optional.push(field.to_owned()),
+            }
+        }
+        Some(MountInfo {
+            id,
+            parent,
+            major: major.parse().ok()?,
+            minor: minor.parse().ok()?,
+            root,
+            target,
+            options,
+            optional,
+            fs_type: fields.next()?.to_owned(),
+            source: unescape(fields.next()?),
+            super_options: fields.next().unwrap_or_default().to_owned(),
+        })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.options.split(',').any(|o| o == "ro")
+    }
+}
+
+// Mounts in the order they were made, a later mount on the same target hides
+// the earlier ones
+#[derive(Debug, Clone, Default)]
+pub struct MountTable {
+    mounts: Vec<MountInfo>,
+}
+
+impl MountTable {
+    pub fn load() -> io::Result<MountTable> {
+        Ok(MountTable::parse(&fs::read_to_string(MOUNTINFO)?))
+    }
+
+    pub fn parse(content: &str) -> MountTable {
+        MountTable {
+            mounts: content.lines().filter_map(MountInfo::parse).collect(),
+        }
+    }
+
+    pub fn mounts(&self) -> &[MountInfo] {
+        &self.mounts
+    }
+
+    pub fn is_mounted(&self, target: &str) -> bool {
+        self.find(target).is_some()
+    }
+
+    // The visible mount at `target`
+    pub fn find(&self, target: &str) -> Option<&MountInfo> {
+        self.mounts.iter().rev().find(|m| m.target == target)
+    }
+
+    // Mounts at or below `dir`, parents before children
+    pub fn mounts_under(&self, dir: &str) -> Vec<&MountInfo> {
+        self.mounts
+            .iter()
+            .filter(|m| is_under(&m.target, dir))
+            .collect()
+    }
+
+    pub fn find_by_source(&self, source: &str) -> Vec<&MountInfo> {
+        self.mounts.iter().filter(|m| m.source == source).collect()
+    }
+
+    pub fn find_by_dev(&self, major: u32, minor: u32) -> Vec<&MountInfo> {
+        self.mounts
+            .iter()
+            .filter(|m| m.major == major && m.minor == minor)
+            .collect()
+    }
+}
@@ -0,0 +1,271 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use Fuseisk::archive::bootimg::{BootImage, BootImgError};
+use Fuseisk::archive::cpio::{Cpio, CpioError};
+use Fuseisk::compress::{self, CompressError, Format};
+use crate::bootmethod::{detect_main, DETECT_USAGE};
+use crate::fuse::{fuse_main, FUSE_USAGE};
+use crate::init::INIT_BACK;
+
+// Host side of fuseisk: put ourselves in a boot image as /init.
+//
+// The original init is kept in the ramdisk as init_back, which is what
+// MagiskInit::restore_ramdisk_init() hands control back to.
+
+const USAGE: &str = "\
+Usage: fuseisk patch <boot.img> <out.img> [--arch <arch>] [--init <file>]
+
+Back up /init of the ramdisk in <boot.img> to /init_back, insert fuseisk as
+/init, and write the repacked image to <out.img>.
+
+  --arch <arch>   x86_64, x86, arm64 or arm, the host architecture by default
+  --init <file>   the fuseisk build to insert, by default the cargo ndk build
+                  for <arch> in ./target";
+
+const EM_386: u16 = 3;
+const EM_ARM: u16 = 40;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+
+// Ramdisks created from scratch use the same format as magiskboot
+const EMPTY_RAMDISK_FORMAT: Format = Format::Lz4Legacy;
+
+#[derive(Debug, Error)]
+pub enum PatchError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    BootImg(#[from] BootImgError),
+    #[error(transparent)]
+    Cpio(#[from] CpioError),
+    #[error(transparent)]
+    Compress(#[from] CompressError),
+    #[error("{0}\n\n{USAGE}")]
+    Usage(String),
+    #[error("unsupported architecture '{0}'")]
+    UnknownArch(String),
+    #[error("{0} is not an {1} binary")]
+    WrongArch(PathBuf, Arch),
+    #[error("no fuseisk build for {0} found, build one or pass --init")]
+    NoPayload(Arch),
+    #[error("vendor_boot images have no init to replace")]
+    VendorBoot,
+}
+
+pub type PatchResult<T> = Result<T, PatchError>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    X86,
+    Arm64,
+    Arm,
+}
+
+impl Arch {
+    fn from_name(name: &str) -> Option<Arch> {
+        match name {
+            "x86_64" | "x64" => Some(Arch::X86_64),
+            "x86" | "i686" => Some(Arch::X86),
+            "arm64" | "aarch64" | "arm64-v8a" => Some(Arch::Arm64),
+            "arm" | "armv7" | "armeabi-v7a" => Some(Arch::Arm),
+            _ => None,
+        }
+    }
+
+    fn host() -> Option<Arch> {
+        Arch::from_name(std::env::consts::ARCH)
+    }
+
+    fn elf_machine(&self) -> u16 {
+        match self {
+            Arch::X86_64 => EM_X86_64,
+            Arch::X86 => EM_386,
+            Arch::Arm64 => EM_AARCH64,
+            Arch::Arm => EM_ARM,
+        }
+    }
+
+    // The target cargo ndk builds for, see build.sh
+    fn android_target(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86_64-linux-android",
+            Arch::X86 => "i686-linux-android",
+            Arch::Arm64 => "aarch64-linux-android",
+            Arch::Arm => "armv7-linux-androideabi",
+        }
+    }
+}
+
+impl std::fmt::Display for Arch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Arch::X86_64 => "x86_64",
+            Arch::X86 => "x86",
+            Arch::Arm64 => "arm64",
+            Arch::Arm => "arm",
+        })
+    }
+}
+
+fn is_elf_for(data: &[u8], arch: Arch) -> bool {
+    data.starts_with(b"\x7fELF")
+        && data
+            .get(18..20)
+            .is_some_and(|m| u16::from_le_bytes([m[0], m[1]]) == arch.elf_machine())
+}
+
+struct PatchArgs {
+    image: PathBuf,
+    out: PathBuf,
+    arch: Arch,
+    init: Option<PathBuf>,
+}
+
+fn parse_args(args: &[String]) -> PatchResult<PatchArgs> {
+    let mut files = Vec::new();
+    let mut arch = None;
+    let mut init = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = |name: &str| {
+            iter.next()
+                .ok_or_else(|| PatchError::Usage(format!("{} needs a value", name)))
+        };
+        match arg.as_str() {
+            "--arch" => {
+                let name = value("--arch")?;
+                arch = Some(
+                    Arch::from_name(name).ok_or_else(|| PatchError::UnknownArch(name.clone()))?,
+                );
+            }
+            "--init" => init = Some(PathBuf::from(value("--init")?)),
+            _ if arg.starts_with("--") => {
+                return Err(PatchError::Usage(format!("unknown option {}", arg)))
+            }
+            _ => files.push(PathBuf::from(arg)),
+        }
+    }
+    let [image, out]: [PathBuf; 2] = files
+        .try_into()
+        .map_err(|_| PatchError::Usage("expected an input and an output image".to_owned()))?;
+    let arch = match arch.or_else(Arch::host) {
+        Some(arch) => arch,
+        None => return Err(PatchError::UnknownArch(std::env::consts::ARCH.to_owned())),
+    };
+    Ok(PatchArgs {
+        image,
+        out,
+        arch,
+        init,
+    })
+}
+
+// The binary that becomes /init, it must run on the device
+fn load_payload(arch: Arch, init: Option<&Path>) -> PatchResult<Vec<u8>> {
+    if let Some(path) = init {
+        let data = fs::read(path)?;
+        if !is_elf_for(&data, arch) {
+            return Err(PatchError::WrongArch(path.to_owned(), arch));
+        }
+        return Ok(data);
+    }
+    let candidates = ["release", "debug"].iter().map(|profile| {
+        Path::new("target")
+            .join(arch.android_target())
+            .join(profile)
+            .join(env!("CARGO_PKG_NAME"))
+    });
+    for path in candidates {
+        if let Ok(data) = fs::read(&path) {
+            if is_elf_for(&data, arch) {
+                println!("- Using {}", path.display());
+                return Ok(data);
+            }
+        }
+    }
+    Err(PatchError::NoPayload(arch))
+}
+
+// Swap /init for the payload, keeping the original as init_back
+pub fn patch_ramdisk(cpio: &mut Cpio, payload: Vec<u8>) -> PatchResult<()> {
+    let backup = INIT_BACK.trim_start_matches('/');
+    if cpio.exists(backup) {
+        // Already patched, only update ourselves
+        println!("- {} exists, replacing init", INIT_BACK);
+    } else if cpio.exists("init") {
+        println!("- Backup init to {}", INIT_BACK);
+        cpio.mv("init", backup)?;
+    } else {
+        // The real init is /system/bin/init, restore_ramdisk_init() links to it
+        println!("- No init in ramdisk");
+    }
+    cpio.add(0o750, "init", payload);
+    Ok(())
+}
+
+pub fn patch_image(image: &Path, out: &Path, payload: Vec<u8>) -> PatchResult<()> {
+    let mut img = BootImage::load_from_file(image)?;
+    if img.is_vendor_boot() {
+        return Err(PatchError::VendorBoot);
+    }
+    println!("- Header version: {}", img.header_version());
+
+    let (format, mut cpio) = if img.ramdisk().is_empty() {
+        (EMPTY_RAMDISK_FORMAT, Cpio::new())
+    } else {
+        let format = Format::detect(img.ramdisk());
+        let raw = if format.is_compressed() {
+            compress::decompress_as(format, img.ramdisk())?
+        } else {
+            img.ramdisk().to_vec()
+        };
+        (format, Cpio::parse(&raw)?)
+    };
+    println!("- Ramdisk format: {}", format);
+
+    patch_ramdisk(&mut cpio, payload)?;
+
+    let raw = cpio.dump();
+    let ramdisk = if format.is_compressed() {
+        compress::compress(format, &raw)?
+    } else {
+        raw
+    };
+    img.set_ramdisk(ramdisk);
+    img.dump_to_file(out)?;
+    println!("- Wrote {}", out.display());
+    Ok(())
+}
+
+pub fn patch_main(args: &[String]) -> PatchResult<()> {
+    let args = parse_args(args)?;
+    let payload = load_payload(args.arch, args.init.as_deref())?;
+    patch_image(&args.image, &args.out, payload)
+}
+
+fn report<E: std::fmt::Display>(res: Result<(), E>) -> i32 {
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("! {}", e);
+            1
+        }
+    }
+}
+
+// Entry point when not running as PID 1, args[0] is the program name
+pub fn main(args: &[String]) -> i32 {
+    match args.get(1).map(String::as_str) {
+        Some("patch") => report(patch_main(&args[2..])),
+        Some("detect") => report(detect_main(&args[2..])),
+        // Errors are logged as they happen
+        Some("fuse") => fuse_main(&args[2..]).map_or(1, |()| 0),
+        _ => {
+            eprintln!("{}\n\n{}\n\n{}", USAGE, DETECT_USAGE, FUSE_USAGE);
+            1
+        }
+    }
+}
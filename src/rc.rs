@@ -0,0 +1,281 @@
+use std::fmt::{self, Display, Formatter};
+use Fuseisk::debug;
+
+// A minimal model of Android init scripts.
+//
+// init.rc is a list of sections. Each section starts with a keyword line
+// (`import`, `on` or `service`) and owns every following line up to the next
+// keyword line. Lines are kept verbatim, so an unmodified script serializes
+// back to exactly what was parsed.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SectionKind {
+    // Comments and blank lines before the first section
+    Preamble,
+    // `import <path>`
+    Import(String),
+    // `on <trigger>`
+    Action(String),
+    // `service <name> <path> [args...]`
+    Service(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub kind: SectionKind,
+    pub header: String,
+    pub body: Vec<String>,
+}
+
+impl Section {
+    // Non empty lines of the body
+    fn commands(&self) -> impl Iterator<Item = &String> {
+        self.body.iter().filter(|l| !l.trim().is_empty())
+    }
+}
+
+fn section_kind(line: &str) -> Option<SectionKind> {
+    let mut tokens = line.split_whitespace();
+    match tokens.next()? {
+        "import" => Some(SectionKind::Import(tokens.collect::<Vec<_>>().join(" "))),
+        "on" => Some(SectionKind::Action(tokens.collect::<Vec<_>>().join(" "))),
+        "service" => Some(SectionKind::Service(tokens.next()?.to_owned())),
+        _ => None,
+    }
+}
+
+// Split into logical lines, joining lines continued with a trailing backslash
+fn logical_lines(content: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut pending: Option<String> = None;
+    for line in content.split('\n') {
+        let mut cur = match pending.take() {
+            Some(mut p) => {
+                p.push('\n');
+                p.push_str(line);
+                p
+            }
+            None => line.to_owned(),
+        };
+        if cur.ends_with('\\') {
+            pending = Some(cur);
+        } else {
+            lines.push(std::mem::take(&mut cur));
+        }
+    }
+    if let Some(p) = pending {
+        lines.push(p);
+    }
+    lines
+}
+
+pub struct InitRc {
+    pub sections: Vec<Section>,
+}
+
+impl InitRc {
+    pub fn parse(content: &str) -> InitRc {
+        let mut sections = vec![Section {
+            kind: SectionKind::Preamble,
+            header: String::new(),
+            body: Vec::new(),
+        }];
+        let mut lines = logical_lines(content);
+        // split('\n') yields a final empty line for newline terminated input
+        if lines.last().is_some_and(|l| l.is_empty()) {
+            lines.pop();
+        }
+        for line in lines {
+            match section_kind(&line) {
+                Some(kind) => sections.push(Section {
+                    kind,
+                    header: line,
+                    body: Vec::new(),
+                }),
+                // There is always the preamble
+                None => sections.last_mut().unwrap().body.push(line),
+            }
+        }
+        InitRc { sections }
+    }
+
+    pub fn find(&self, kind: &SectionKind) -> Option<&Section> {
+        self.sections.iter().find(|s| &s.kind == kind)
+    }
+
+    pub fn apply(&mut self, patch: &RcPatch) {
+        for rewrite in &patch.rewrites {
+            self.rewrite(rewrite);
+        }
+        for path in &patch.imports {
+            self.add_import(path);
+        }
+        for section in &patch.sections {
+            self.inject(section);
+        }
+    }
+
+    fn rewrite(&mut self, rewrite: &RcRewrite) {
+        // Without a replacement a keyword line takes its section with it
+        if rewrite.to.is_empty() {
+            self.sections.retain(|section| {
+                let matched = section.kind != SectionKind::Preamble
+                    && section.header.trim_start().starts_with(&rewrite.from);
+                if matched {
+                    debug!("Remove [{}]", section.header);
+                }
+                !matched
+            });
+        }
+        for section in &mut self.sections {
+            if section.header.trim_start().starts_with(&rewrite.from) {
+                debug!("Rewrite [{}] -> [{}]", section.header, rewrite.to);
+                // Keep the section when the replacement is not a keyword line,
+                // its body still belongs somewhere
+                section.header = rewrite.to.clone();
+                if let Some(kind) = section_kind(&section.header) {
+                    section.kind = kind;
+                }
+            }
+            section.body.retain_mut(|line| {
+                let trimmed = line.trim_start();
+                if !trimmed.starts_with(&rewrite.from) {
+                    return true;
+                }
+                debug!("Rewrite [{}] -> [{}]", trimmed, rewrite.to);
+                if rewrite.to.is_empty() {
+                    return false;
+                }
+                let indent = line.len() - trimmed.len();
+                *line = format!("{}{}", &line[..indent], rewrite.to);
+                true
+            });
+        }
+    }
+
+    fn add_import(&mut self, path: &str) {
+        let kind = SectionKind::Import(path.to_owned());
+        if self.find(&kind).is_some() {
+            return;
+        }
+        // Right after the existing imports, or after the preamble
+        let pos = self
+            .sections
+            .iter()
+            .rposition(|s| matches!(s.kind, SectionKind::Import(_) | SectionKind::Preamble))
+            .map_or(0, |i| i + 1);
+        // Blank lines separating the imports from what follows stay after us
+        let mut body = Vec::new();
+        if let Some(prev) = pos.checked_sub(1).map(|i| &mut self.sections[i]) {
+            let keep = prev
+                .body
+                .iter()
+                .rposition(|l| !l.trim().is_empty())
+                .map_or(0, |i| i + 1);
+            body = prev.body.split_off(keep);
+        }
+        self.sections.insert(
+            pos,
+            Section {
+                kind,
+                header: format!("import {}", path),
+                body,
+            },
+        );
+    }
+
+    fn inject(&mut self, section: &Section) {
+        match &section.kind {
+            SectionKind::Action(_) => {
+                // Extend the first action with the same trigger
+                if let Some(existing) = self.sections.iter_mut().find(|s| s.kind == section.kind) {
+                    let at = existing
+                        .body
+                        .iter()
+                        .rposition(|l| !l.trim().is_empty())
+                        .map_or(0, |i| i + 1);
+                    let cmds: Vec<String> = section.commands().cloned().collect();
+                    existing.body.splice(at..at, cmds);
+                    return;
+                }
+            }
+            SectionKind::Service(_) => {
+                // Services are unique by name, ours take over
+                if let Some(existing) = self.sections.iter_mut().find(|s| s.kind == section.kind) {
+                    existing.header = section.header.clone();
+                    existing.body = section.commands().cloned().collect();
+                    existing.body.push(String::new());
+                    return;
+                }
+            }
+            SectionKind::Import(path) => {
+                self.add_import(path);
+                return;
+            }
+            SectionKind::Preamble => return,
+        }
+
+        if let Some(last) = self.sections.last_mut() {
+            if last.body.last().is_some_and(|l| !l.trim().is_empty())
+                || (last.body.is_empty() && last.kind != SectionKind::Preamble)
+            {
+                last.body.push(String::new());
+            }
+        }
+        let mut section = section.clone();
+        section.body = section.commands().cloned().collect();
+        section.body.push(String::new());
+        self.sections.push(section);
+    }
+}
+
+impl Display for InitRc {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for section in &self.sections {
+            if section.kind != SectionKind::Preamble {
+                writeln!(f, "{}", section.header)?;
+            }
+            for line in &section.body {
+                writeln!(f, "{}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Replace every line starting with `from` by `to`, an empty `to` drops the line,
+// or the whole section for a keyword line
+#[derive(Debug, Clone)]
+pub struct RcRewrite {
+    pub from: String,
+    pub to: String,
+}
+
+// Everything we inject into an init.rc
+#[derive(Default)]
+pub struct RcPatch {
+    pub imports: Vec<String>,
+    pub sections: Vec<Section>,
+    pub rewrites: Vec<RcRewrite>,
+}
+
+impl RcPatch {
+    // Merge the sections of an rc script: imports are inserted, actions are
+    // appended to the action with the same trigger, and services are added
+    pub fn add_script(&mut self, script: &str) {
+        for section in InitRc::parse(script).sections {
+            match section.kind {
+                SectionKind::Preamble => {}
+                SectionKind::Import(path) => self.imports.push(path),
+                _ => self.sections.push(section),
+            }
+        }
+    }
+
+    pub fn add_rewrite(&mut self, from: &str, to: &str) {
+        self.rewrites.push(RcRewrite {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        });
+    }
+}
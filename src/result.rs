@@ -350,6 +350,10 @@ impl OsError<'_> {
         Self::with_os_error(Errno::last_raw(), name, arg1, arg2)
     }
 
+    pub fn code(&self) -> i32 {
+        self.code
+    }
+
     pub fn set_args<'a>(self, arg1: Option<&'a str>, arg2: Option<&'a str>) -> OsError<'a> {
         Self::with_os_error(self.code, self.name, arg1, arg2)
     }
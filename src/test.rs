@@ -3,9 +3,33 @@ mod tests {
     use std::{
         ffi::CString,
         fs,
-        io::{stdout, IoSlice, Write},
+        io::{self, stdout, IoSlice, Write},
     };
-    use crate::bootconfig::{parse_bootconfig, parse_cmdline};
+    use crate::bootconfig::{
+        parse_bootconfig, parse_cmdline, parse_dt, parse_partition_map, parameq, BootConfig,
+        ConfigSource, Value,
+    };
+    use std::path::Path;
+    use crate::block::BlockDevices;
+    use crate::bootmethod::{detect, BootMethod, FsProbe, RootDir};
+    use crate::init::{
+        default_rc_patch, find_init_rc, hijack_init_with_switch_root, patch_init_rc, INJECT_RC,
+    };
+    use Fuseisk::MountStack;
+    use Fuseisk::cstr::Utf8CStr;
+    use crate::fstab::{first_stage_fstab, parse_fstab};
+    use crate::fuse::{parse_fuse_conf, PassthroughFs};
+    use Fuseisk::fusedev::{Attr, DirEntry, Filesystem, Session, FUSE_ROOT_ID};
+    use std::cell::Cell;
+    use std::ffi::{OsStr, OsString};
+    use std::rc::Rc;
+    use crate::patch::patch_ramdisk;
+    use crate::rc::{InitRc, RcPatch};
+    use Fuseisk::archive::cpio::Cpio;
+    use Fuseisk::archive::bootimg::{BootImage, BootImgError, Section};
+    use sha1::{Digest, Sha1};
+    use Fuseisk::compress::{compress, decompress, decompress_as, Format};
+    use Fuseisk::mountinfo::MountTable;
     // 注意这个惯用法：在 tests 模块中，从外部作用域导入所有名字。
     use super::*;
 
@@ -28,7 +52,7 @@ mod tests {
         let content = fs::read_to_string(file_path).unwrap();
 
         // 使用 parse_kv 解析
-        let kv_pairs = parse_cmdline(&content);
+        let kv_pairs = parse_cmdline(&content).params;
 
         // 打印结果
         for (key, value) in kv_pairs {
@@ -36,6 +60,38 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_cmdline_quotes() {
+        let cmdline = parse_cmdline(
+            "console=ttyS0 androidboot.foo=\"a b\" dyndbg=\"file x.c +p\" \"quoted=c d\" \
+             rootwait usbcore.auto-suspend=7 -- single \"x y\" a=b\n",
+        );
+        let get = |key: &str| {
+            cmdline
+                .params
+                .iter()
+                .find(|(k, _)| parameq(k, key))
+                .map(|(_, v)| v.to_string())
+        };
+
+        assert_eq!(get("console").as_deref(), Some("ttyS0"));
+        assert_eq!(get("androidboot.foo").as_deref(), Some("a b"));
+        assert_eq!(get("dyndbg").as_deref(), Some("file x.c +p"));
+        assert_eq!(get("quoted").as_deref(), Some("c d"));
+        assert_eq!(get("rootwait").as_deref(), Some(""));
+        // 模块参数中 - 和 _ 等价
+        assert_eq!(get("usbcore.auto_suspend").as_deref(), Some("7"));
+        assert!(parameq("usbcore.auto-suspend", "usbcore.auto_suspend"));
+        assert!(!parameq("usbcore.autosuspend", "usbcore.auto_suspend"));
+        let mut config = BootConfig::default();
+        config.set(parse_cmdline("androidboot.force-normal-boot=1 skip-initramfs").params);
+        assert!(config.force_normal_boot && config.skip_initramfs);
+        assert_eq!(cmdline.params.len(), 6);
+
+        // -- 之后的参数交给 init
+        assert_eq!(cmdline.init_args, ["single", "x y", "a=b"]);
+    }
+
     #[test]
     fn test_bootconfig_parst() {
         // 获取当前工作目录
@@ -58,8 +114,1376 @@ mod tests {
         let kv_pairs = parse_bootconfig(&content);
 
         // 打印结果
-        for (key, value) in kv_pairs {
+        for (key, value) in &kv_pairs {
             println!("{} = {}", key, value);
         }
+
+        let boot_devices = kv_pairs
+            .iter()
+            .find(|(k, _)| k == "androidboot.boot_devices")
+            .map(|(_, v)| v.clone());
+        assert_eq!(
+            boot_devices,
+            Some(Value::List(vec![
+                "bootdevice".to_owned(),
+                "soc/112b0000.ufshci".to_owned(),
+                "112b0000.ufshci".to_owned(),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_bootconfig_grammar() {
+        let content = r#"
+# 注释
+androidboot.hardware = cutf_cvm   # 行尾注释
+androidboot {
+    slot_suffix = "_a"; mode = normal
+    boot_devices = "pci0000:00/0000:00:03.0",
+                   # 数组中的注释
+                   "pci0000:00/0000:00:04.0"
+    verifiedbootstate
+}
+androidboot.boot_devices += 'soc/1d84000.ufshc'
+androidboot.mode := charger
+quoted = "a;b#c", 'say "hi"', "esc\"aped\\"
+long = first second
+"#;
+        let kv = parse_bootconfig(content);
+        let get = |key: &str| kv.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+        let scalar = |s: &str| Some(Value::Scalar(s.to_owned()));
+
+        assert_eq!(get("androidboot.hardware"), scalar("cutf_cvm"));
+        assert_eq!(get("androidboot.slot_suffix"), scalar("_a"));
+        assert_eq!(get("androidboot.mode"), scalar("charger"));
+        assert_eq!(get("androidboot.verifiedbootstate"), scalar(""));
+        assert_eq!(
+            get("androidboot.boot_devices").unwrap().items(),
+            [
+                "pci0000:00/0000:00:03.0",
+                "pci0000:00/0000:00:04.0",
+                "soc/1d84000.ufshc",
+            ]
+        );
+        assert_eq!(
+            get("quoted"),
+            Some(Value::List(vec![
+                "a;b#c".to_owned(),
+                "say \"hi\"".to_owned(),
+                "esc\"aped\\".to_owned(),
+            ]))
+        );
+        assert_eq!(get("long"), scalar("first second"));
+        assert_eq!(kv.len(), 7);
+    }
+
+    #[test]
+    fn test_dt_parse() {
+        let dir = std::env::temp_dir().join(format!("fuseisk-dt-{}", std::process::id()));
+        fs::create_dir_all(dir.join("fstab/system")).unwrap();
+        fs::write(dir.join("compatible"), "android,firmware\0").unwrap();
+        fs::write(dir.join("name"), "android\0").unwrap();
+        fs::write(dir.join("hardware"), "ranchu\0").unwrap();
+        fs::write(dir.join("fstab_suffix"), "ranchu,avd\0").unwrap();
+        let dt = parse_dt(dir.to_str().unwrap()).unwrap();
+
+        // 只读取属性文件，逗号替换为点
+        assert_eq!(
+            dt,
+            vec![
+                (
+                    "androidboot.fstab_suffix".to_owned(),
+                    Value::Scalar("ranchu.avd".to_owned())
+                ),
+                (
+                    "androidboot.hardware".to_owned(),
+                    Value::Scalar("ranchu".to_owned())
+                ),
+            ]
+        );
+
+        // compatible 不匹配时忽略整个目录
+        fs::write(dir.join("compatible"), "vendor,firmware\0").unwrap();
+        assert!(parse_dt(dir.to_str().unwrap()).unwrap().is_empty());
+        assert!(parse_dt("/nonexistent").unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_missing_sources() {
+        let dir = std::env::temp_dir().join(format!("fuseisk-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cmdline = dir.join("cmdline");
+        let bootconfig = dir.join("bootconfig");
+        let missing = dir.join("missing");
+        fs::write(&cmdline, "androidboot.slot_suffix=_b rootwait\n").unwrap();
+        fs::write(&bootconfig, "androidboot.hardware = \"cutf_cvm\"\n").unwrap();
+        let load = |cmdline: &Path, bootconfig: &Path| {
+            let mut config = BootConfig {
+                dt_dir: dir.join("dt").to_str().unwrap().to_owned(),
+                ..Default::default()
+            };
+            let errors = config.load(cmdline, bootconfig);
+            let sources: Vec<ConfigSource> = errors
+                .iter()
+                .filter_map(|r| r.as_ref().err())
+                .map(|e| e.0)
+                .collect();
+            (config, sources)
+        };
+
+        // 5.10 之前的内核没有 /proc/bootconfig
+        let (config, errors) = load(&cmdline, &missing);
+        assert_eq!(errors, [ConfigSource::Bootconfig]);
+        assert_eq!(config.slot, "_b");
+        assert!(config.rootwait);
+
+        // 没有 cmdline 时仍然读取 bootconfig
+        let (config, errors) = load(&missing, &bootconfig);
+        assert_eq!(errors, [ConfigSource::Cmdline]);
+        assert_eq!(config.hardware, "cutf_cvm");
+        assert!(config.slot.is_empty());
+
+        // 全部缺失时使用默认值
+        let (config, errors) = load(&missing, &missing);
+        assert_eq!(errors, [ConfigSource::Cmdline, ConfigSource::Bootconfig]);
+        assert!(!config.rootwait);
+        assert!(config.hardware.is_empty());
+
+        // 没有设备树目录不算错误
+        let (config, errors) = load(&cmdline, &bootconfig);
+        assert!(errors.is_empty());
+        assert_eq!((config.slot.as_str(), config.hardware.as_str()), ("_b", "cutf_cvm"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_override_order() {
+        let dir = std::env::temp_dir().join(format!("fuseisk-order-{}", std::process::id()));
+        let dt = dir.join("dt");
+        fs::create_dir_all(&dt).unwrap();
+        fs::write(dt.join("compatible"), "android,firmware\0").unwrap();
+        fs::write(dt.join("hardware"), "dt_hw\0").unwrap();
+        let cmdline = dir.join("cmdline");
+        let bootconfig = dir.join("bootconfig");
+        fs::write(
+            &cmdline,
+            "androidboot.hardware=cmd_hw androidboot.slot_suffix=_a androidboot.slot_suffix=_b \
+             androidboot.partition_map=vdb,metadata;vdb,userdata;vdc,cache\n",
+        )
+        .unwrap();
+        fs::write(
+            &bootconfig,
+            format!(
+                "androidboot.android_dt_dir = \"{}\"\n\
+                 androidboot.hardware = boot_hw\n\
+                 androidboot.slot_suffix = _b\n\
+                 androidboot.mode = charger\n\
+                 androidboot.partition_map = \"vdd,system\"\n",
+                dt.display()
+            ),
+        )
+        .unwrap();
+
+        let mut config = BootConfig::default();
+        assert!(config.load(&cmdline, &bootconfig).iter().all(Result::is_ok));
+        // 与 init 相同：先设备树，再 cmdline，最后 bootconfig，先设置的值生效
+        assert_eq!(config.dt_dir, dt.to_str().unwrap());
+        assert_eq!(config.hardware, "dt_hw");
+        assert_eq!(config.slot, "_a");
+        assert_eq!(config.mode, "charger");
+        // 同一设备只保留第一个映射
+        let map: Vec<(&str, &str)> = config
+            .partition_map
+            .iter()
+            .map(|kv| (kv.key.as_str(), kv.value.as_str()))
+            .collect();
+        assert_eq!(map, [("vdb", "metadata"), ("vdc", "cache")]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fstab_parse() {
+        let entries = parse_fstab(
+            "\
+# 注释
+system /system erofs ro wait,slotselect,avb=vbmeta_system,logical,first_stage_mount
+/dev/block/by-name/metadata /metadata ext4 noatime,nosuid,nodev,discard wait,formattable
+invalid line
+",
+        );
+        assert_eq!(entries.len(), 2);
+
+        let system = &entries[0];
+        assert_eq!(system.mount_point, "/system");
+        assert_eq!(system.fs_type, "erofs");
+        assert_eq!(system.flags, libc::MS_RDONLY);
+        let flags = &system.fs_mgr_flags;
+        assert!(flags.wait && flags.slotselect && flags.avb && flags.logical);
+        assert!(flags.first_stage_mount);
+        assert_eq!(flags.vbmeta_partition.as_deref(), Some("vbmeta_system"));
+
+        let metadata = &entries[1];
+        assert_eq!(metadata.partname(), "metadata");
+        assert_eq!(
+            metadata.flags,
+            libc::MS_NOATIME | libc::MS_NOSUID | libc::MS_NODEV
+        );
+        assert_eq!(metadata.fs_options, "discard");
+        assert_eq!(metadata.fs_mgr_flags.other, ["formattable"]);
+    }
+
+    #[test]
+    fn test_first_stage_fstab() {
+        let root = std::env::temp_dir().join(format!("fuseisk-fstab-{}", std::process::id()));
+        let dt_system = root.join("dt/fstab/system");
+        fs::create_dir_all(&dt_system).unwrap();
+        fs::create_dir_all(root.join("first_stage_ramdisk")).unwrap();
+        fs::write(dt_system.join("dev"), "/dev/block/by-name/system\0").unwrap();
+        fs::write(dt_system.join("type"), "ext4\0").unwrap();
+        fs::write(dt_system.join("mnt_flags"), "ro\0").unwrap();
+        fs::write(dt_system.join("fsmgr_flags"), "wait,slotselect\0").unwrap();
+        fs::write(
+            root.join("first_stage_ramdisk/fstab.cutf"),
+            "\
+system /system ext4 ro wait,logical,first_stage_mount
+vendor /vendor ext4 ro wait,logical,first_stage_mount,slotselect
+/dev/block/by-name/userdata /data ext4 noatime wait
+/dev/block/by-name/userdata /data f2fs noatime wait
+",
+        )
+        .unwrap();
+
+        let config = BootConfig {
+            slot: "_a".to_owned(),
+            hardware: "cutf".to_owned(),
+            dt_dir: root.join("dt").to_str().unwrap().to_owned(),
+            ..Default::default()
+        };
+        let entries = first_stage_fstab(root.to_str().unwrap(), &config);
+        let devices: Vec<(&str, &str)> = entries
+            .iter()
+            .map(|e| (e.mount_point.as_str(), e.blk_device.as_str()))
+            .collect();
+        // 设备树优先，slotselect 加上当前槽位
+        assert_eq!(
+            devices,
+            [
+                ("/system", "/dev/block/by-name/system_a"),
+                ("/vendor", "vendor_a"),
+                ("/data", "/dev/block/by-name/userdata"),
+                ("/data", "/dev/block/by-name/userdata"),
+            ]
+        );
+        assert_eq!(entries[0].fs_type, "ext4");
+        assert!(!entries[0].fs_mgr_flags.logical);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_block_devices() {
+        let sys = std::env::temp_dir().join(format!("fuseisk-block-{}", std::process::id()));
+        for (dir, uevent) in [
+            ("259:1", "MAJOR=259\nMINOR=1\nDEVNAME=sda1\nDEVTYPE=partition\nPARTNAME=system_a\n"),
+            ("259:2", "MAJOR=259\nMINOR=2\nDEVNAME=sda2\nDEVTYPE=partition\nPARTNAME=system_b\n"),
+            ("259:3", "MAJOR=259\nMINOR=3\nDEVNAME=sda3\nDEVTYPE=partition\nPARTNAME=metadata\n"),
+            ("252:16", "MAJOR=252\nMINOR=16\nDEVNAME=vdb\nDEVTYPE=disk\n"),
+            ("1:0", "DEVNAME=ram0\n"),
+        ] {
+            fs::create_dir_all(sys.join(dir)).unwrap();
+            fs::write(sys.join(dir).join("uevent"), uevent).unwrap();
+        }
+
+        let mut config = BootConfig {
+            slot: "_b".to_owned(),
+            ..Default::default()
+        };
+        config.set(vec![(
+            "androidboot.partition_map".to_owned(),
+            Value::Scalar("vdb,userdata".to_owned()),
+        )]);
+        let mut blocks = BlockDevices::with_dirs(sys.to_str().unwrap(), "/nonexistent", &config);
+        blocks.scan();
+        assert_eq!(blocks.devices().len(), 4);
+
+        // 优先使用当前槽位
+        assert_eq!(blocks.find("system").map(|d| d.minor), Some(2));
+        assert_eq!(blocks.find("system_a").map(|d| d.minor), Some(1));
+        assert_eq!(blocks.find("METADATA").map(|d| d.minor), Some(3));
+        // 没有 PARTNAME 的设备通过 partition_map 命名
+        assert_eq!(blocks.find("userdata").map(|d| d.major), Some(252));
+        assert!(blocks.find("vendor").is_none());
+
+        fs::remove_dir_all(&sys).unwrap();
+    }
+
+    #[test]
+    fn test_partition_map_parse() {
+        assert_eq!(
+            parse_partition_map("vdb,metadata;vdc,userdata;"),
+            vec![
+                ("vdb".to_owned(), "metadata".to_owned()),
+                ("vdc".to_owned(), "userdata".to_owned()),
+            ]
+        );
+        // 格式错误的项被跳过
+        assert_eq!(
+            parse_partition_map("vdb;vdc,userdata,extra;vdd,system_ext"),
+            vec![("vdd".to_owned(), "system_ext".to_owned())]
+        );
+        assert!(parse_partition_map("").is_empty());
+    }
+
+    #[test]
+    fn test_init_rc_patch() {
+        let content = "\
+import /init.environ.rc
+
+on early-init
+    start ueventd
+
+service flash_recovery /system/bin/install-recovery.sh
+    class main
+    oneshot
+";
+        // 未修改时必须原样输出
+        assert_eq!(InitRc::parse(content).to_string(), content);
+
+        let mut patch = RcPatch::default();
+        patch.add_script(
+            "\
+import /data/foo.rc
+on early-init
+    write /dev/foo 1
+service foo /system/bin/foo
+    oneshot
+on property:sys.boot_completed=1
+    start foo
+",
+        );
+        patch.add_rewrite("service flash_recovery", "service flash_recovery /system/bin/true");
+
+        let mut rc = InitRc::parse(content);
+        rc.apply(&patch);
+        assert_eq!(
+            rc.to_string(),
+            "\
+import /init.environ.rc
+import /data/foo.rc
+
+on early-init
+    start ueventd
+    write /dev/foo 1
+
+service flash_recovery /system/bin/true
+    class main
+    oneshot
+
+service foo /system/bin/foo
+    oneshot
+
+on property:sys.boot_completed=1
+    start foo
+
+"
+        );
+    }
+
+    #[test]
+    fn test_init_rc_remove_section() {
+        let mut patch = RcPatch::default();
+        patch.add_rewrite("service flash_recovery", "");
+        patch.add_rewrite("oneshot", "");
+
+        let mut rc = InitRc::parse(
+            "\
+on early-init
+    start ueventd
+    oneshot
+
+service flash_recovery /system/bin/install-recovery.sh
+    class main
+    oneshot
+
+service foo /system/bin/foo
+    oneshot
+",
+        );
+        rc.apply(&patch);
+        // 空的替换删除整个 section，不留下空的 header
+        assert_eq!(
+            rc.to_string(),
+            "\
+on early-init
+    start ueventd
+
+service foo /system/bin/foo
+"
+        );
+    }
+
+    #[test]
+    fn test_legacy_sar_init_rc() {
+        let root = std::env::temp_dir().join(format!("fuseisk-sar-{}", std::process::id()));
+        fs::create_dir_all(root.join("system/etc/init/hw")).unwrap();
+        fs::write(
+            root.join("init.rc"),
+            "on early-init\n    start ueventd\n\nservice flash_recovery /system/bin/install-recovery.sh\n",
+        )
+        .unwrap();
+        let fake = RootDir::new(root.to_str().unwrap());
+
+        // Android 9 的 legacy SAR：init.rc 在 system 根目录
+        assert_eq!(find_init_rc(BootMethod::LegacySar, &fake), Some("/init.rc"));
+        assert_eq!(find_init_rc(BootMethod::TwoStage, &fake), None);
+        fs::write(root.join("system/etc/init/hw/init.rc"), "").unwrap();
+        assert_eq!(find_init_rc(BootMethod::LegacySar, &fake), Some("/init.rc"));
+        assert_eq!(
+            find_init_rc(BootMethod::TwoStage, &fake),
+            Some("/system/etc/init/hw/init.rc")
+        );
+
+        let mut src = root.join("init.rc").to_str().unwrap().to_owned();
+        let mut dest = root.join("patched.rc").to_str().unwrap().to_owned();
+        fs::write(&dest, "").unwrap();
+        patch_init_rc(
+            Utf8CStr::from_string(&mut src),
+            Utf8CStr::from_string(&mut dest),
+            &default_rc_patch(),
+        )
+        .unwrap();
+        let patched = fs::read_to_string(&dest).unwrap();
+        assert!(patched.contains("service flash_recovery /system/bin/true\n"));
+        // 所有启动方式都注入我们自己的服务
+        assert!(patched.contains("\non post-fs-data\n    start fuseisk_post_fs_data\n"));
+        assert!(patched.ends_with(&format!("{}\n", INJECT_RC)));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_hijack_rollback() {
+        let root = std::env::temp_dir().join(format!("fuseisk-hijack-{}", std::process::id()));
+        for force_normal_boot in [false, true] {
+            fs::create_dir_all(root.join("first_stage_ramdisk")).unwrap();
+            // 悬空的 /init 让两种 bind mount 都失败，不论是否有 root 权限
+            std::os::unix::fs::symlink("/nonexistent/init", root.join("init")).unwrap();
+
+            let mut mounts = MountStack::default();
+            mounts.push("/fuseisk-test/proc");
+            let guard = mounts.guard();
+            let root_dir = root.to_str().unwrap();
+            let res = hijack_init_with_switch_root(root_dir, force_normal_boot, &mut mounts);
+            assert!(res.is_err());
+            assert!(fs::symlink_metadata(root.join("sdcard")).is_ok());
+            mounts.rollback(guard);
+            assert_eq!(mounts.len(), 1);
+
+            // /init 恢复原位，symlink 与创建的文件全部删除
+            assert_eq!(
+                fs::read_link(root.join("init")).unwrap(),
+                Path::new("/nonexistent/init")
+            );
+            for path in ["sdcard", "storage", "first_stage_ramdisk/sdcard"] {
+                assert!(fs::symlink_metadata(root.join(path)).is_err(), "{}", path);
+            }
+            // first_stage_ramdisk 本身保留，但其中不留任何东西
+            let mut ramdisk = fs::read_dir(root.join("first_stage_ramdisk")).unwrap();
+            assert!(ramdisk.next().is_none());
+            fs::remove_dir_all(&root).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_patch_ramdisk() {
+        let mut cpio = Cpio::new();
+        cpio.add(0o750, "init", b"stock init".to_vec());
+        cpio.mkdir(0o755, "system");
+
+        patch_ramdisk(&mut cpio, b"fuseisk".to_vec()).unwrap();
+        assert_eq!(cpio.find("init").unwrap().data, b"fuseisk");
+        assert_eq!(cpio.find("init_back").unwrap().data, b"stock init");
+
+        // 再次修补时保留原始 init
+        patch_ramdisk(&mut cpio, b"fuseisk v2".to_vec()).unwrap();
+        assert_eq!(cpio.find("init").unwrap().data, b"fuseisk v2");
+        assert_eq!(cpio.find("init_back").unwrap().data, b"stock init");
+
+        // 没有 init 的 ramdisk 只添加自己
+        let mut cpio = Cpio::new();
+        patch_ramdisk(&mut cpio, b"fuseisk".to_vec()).unwrap();
+        assert!(cpio.exists("init"));
+        assert!(!cpio.exists("init_back"));
+    }
+
+    // 内存中的 rootfs，路径映射到 (inode, 内容)
+    struct MockFs(Vec<(&'static str, u64, &'static [u8])>);
+
+    impl FsProbe for MockFs {
+        fn exists(&self, path: &str) -> bool {
+            self.0.iter().any(|(p, _, _)| *p == path)
+        }
+
+        fn read(&self, path: &str) -> Option<Vec<u8>> {
+            self.0.iter().find(|(p, _, _)| *p == path).map(|(_, _, d)| d.to_vec())
+        }
+
+        fn file_id(&self, path: &str) -> Option<(u64, u64)> {
+            self.0.iter().find(|(p, _, _)| *p == path).map(|(_, ino, _)| (1, *ino))
+        }
+    }
+
+    #[test]
+    fn test_detect_boot_method() {
+        let config = BootConfig {
+            dt_dir: "/proc/device-tree/firmware/android".to_owned(),
+            hardware: "cutf".to_owned(),
+            ..Default::default()
+        };
+
+        // cmdline 优先于 ramdisk 中的文件
+        let recovery = MockFs(vec![("/system/bin/recovery", 2, b"")]);
+        let sar = BootConfig {
+            skip_initramfs: true,
+            ..Default::default()
+        };
+        assert_eq!(detect(&sar, &recovery).method, BootMethod::LegacySar);
+        let normal = BootConfig {
+            force_normal_boot: true,
+            ..Default::default()
+        };
+        assert_eq!(detect(&normal, &recovery).method, BootMethod::TwoStage);
+
+        let detection = detect(&config, &recovery);
+        assert_eq!(detection.method, BootMethod::Recovery);
+        assert_eq!(detection.evidence, ["/system/bin/recovery exists"]);
+
+        // 2SI ramdisk：/init_back 是原始 init，与 /system/bin/init 不同
+        let two_stage = MockFs(vec![
+            ("/init", 1, b"fuseisk"),
+            ("/init_back", 2, b"init"),
+            ("/system/bin/init", 3, b"init"),
+            ("/first_stage_ramdisk", 4, b""),
+            (
+                "/first_stage_ramdisk/fstab.cutf",
+                5,
+                b"system /system ext4 ro wait,logical,first_stage_mount\n",
+            ),
+        ]);
+        let detection = detect(&config, &two_stage);
+        assert_eq!(detection.method, BootMethod::TwoStage);
+        assert_eq!(
+            detection.evidence,
+            [
+                "/first_stage_ramdisk exists",
+                "/system/bin/init differs from /init",
+                "first_stage_mount in /first_stage_ramdisk/fstab.cutf",
+            ]
+        );
+
+        // 没有其他迹象时，查看原始 init 是否处理 selinux_setup
+        let selinux = MockFs(vec![("/init_back", 2, b"\0selinux_setup\0")]);
+        let detection = detect(&config, &selinux);
+        assert_eq!(detection.method, BootMethod::TwoStage);
+        assert_eq!(detection.evidence, ["/init_back handles selinux_setup"]);
+
+        // system 是指向 init 的链接，属于 rootfs
+        let rootfs = MockFs(vec![
+            ("/init", 1, b"init"),
+            ("/system/bin/init", 1, b"init"),
+            ("/fstab.cutf", 5, b"/dev/block/system /system ext4 ro wait\n"),
+        ]);
+        assert_eq!(detect(&config, &rootfs).method, BootMethod::RootFs);
+    }
+
+    #[test]
+    fn test_fuse_conf_parse() {
+        let mounts = parse_fuse_conf(
+            "\
+# 注释
+/debug_ramdisk/preinit/hosts   /system/etc/hosts
+
+/missing_target
+/a /b /c
+",
+        );
+        // 只接受 <dir> <mountpoint> 两列
+        let expected = ("/debug_ramdisk/preinit/hosts", "/system/etc/hosts");
+        assert_eq!(mounts.len(), 1);
+        assert_eq!((mounts[0].0.as_str(), mounts[0].1.as_str()), expected);
+        assert!(parse_fuse_conf("").is_empty());
+    }
+
+    #[test]
+    fn test_passthrough_fs() {
+        let root = std::env::temp_dir().join(format!("fuseisk-fuse-{}", std::process::id()));
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/hosts"), "127.0.0.1 localhost\n").unwrap();
+        std::os::unix::fs::symlink("etc/hosts", root.join("hosts")).unwrap();
+
+        let mut pfs = PassthroughFs::new(&root);
+        let etc = pfs.lookup(FUSE_ROOT_ID, OsStr::new("etc")).unwrap();
+        assert_eq!(etc.kind(), libc::S_IFDIR);
+        // 同一路径总是得到同一个 inode
+        assert_eq!(pfs.lookup(FUSE_ROOT_ID, OsStr::new("etc")).unwrap().ino, etc.ino);
+        let hosts = pfs.lookup(etc.ino, OsStr::new("hosts")).unwrap();
+        assert_eq!((hosts.kind(), hosts.size), (libc::S_IFREG, 20));
+        assert_eq!(pfs.getattr(hosts.ino).unwrap().ino, hosts.ino);
+        assert!(pfs.lookup(etc.ino, OsStr::new("missing")).is_err());
+
+        let link = pfs.lookup(FUSE_ROOT_ID, OsStr::new("hosts")).unwrap();
+        assert_eq!(link.kind(), libc::S_IFLNK);
+        assert_eq!(pfs.readlink(link.ino).unwrap(), b"etc/hosts");
+
+        // 目录项按名字排序，"." 和 ".." 在最前
+        let names: Vec<_> = pfs
+            .readdir(FUSE_ROOT_ID)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.ino, e.name.into_string().unwrap()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                (FUSE_ROOT_ID, ".".to_owned()),
+                (FUSE_ROOT_ID, "..".to_owned()),
+                (etc.ino, "etc".to_owned()),
+                (link.ino, "hosts".to_owned()),
+            ]
+        );
+        assert_eq!(pfs.readdir(etc.ino).unwrap()[1].ino, FUSE_ROOT_ID);
+
+        // 只读：写方式打开被拒绝
+        assert!(pfs.open(hosts.ino, libc::O_RDWR).is_err());
+        assert!(pfs.read(hosts.ino, 1, 0, 16).is_err());
+        let fh = pfs.open(hosts.ino, libc::O_RDONLY).unwrap();
+        assert_eq!(pfs.read(hosts.ino, fh, 10, 64).unwrap(), b"localhost\n");
+        pfs.release(hosts.ino, fh);
+        assert!(pfs.read(hosts.ino, fh, 0, 16).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_cpio_round_trip() {
+        let mut cpio = Cpio::new();
+        cpio.mkdir(0o755, "system");
+        cpio.mkdir(0o755, "system/bin");
+        cpio.add(0o750, "init", b"\x7fELF init".to_vec());
+        cpio.ln("../../init", "system/bin/init");
+        cpio.add(0o644, "system/bin/odd", vec![1, 2, 3, 4, 5]);
+        let mut data = cpio.dump();
+        // Ramdisks are usually padded to a block boundary
+        data.resize((data.len() / 512 + 1) * 512, 0);
+
+        let parsed = Cpio::parse(&data).unwrap();
+        assert_eq!(parsed.dump(), data);
+        assert_eq!(parsed.entries().len(), 5);
+        assert_eq!(parsed.find("/init").unwrap().data, b"\x7fELF init");
+        assert!(parsed.find("system/bin/init").unwrap().is_symlink());
+
+        // Header case is preserved too
+        let upper = String::from_utf8(data.clone())
+            .unwrap()
+            .replace("000001ed", "000001ED");
+        let parsed = Cpio::parse(upper.as_bytes()).unwrap();
+        assert_eq!(parsed.dump(), upper.as_bytes());
+    }
+
+    #[test]
+    fn test_cpio_exact_headers() {
+        let mut cpio = Cpio::new();
+        cpio.add(0o750, "init", b"init".to_vec());
+        cpio.mkdir(0o755, "system");
+        let data = String::from_utf8(cpio.dump()).unwrap();
+
+        // Only the header of init in upper case
+        let mixed = data.replacen("000081e8", "000081E8", 1);
+        let parsed = Cpio::parse(mixed.as_bytes()).unwrap();
+        assert_eq!(parsed.dump(), mixed.as_bytes());
+
+        // A name padded with extra NULs, 110 + 8 bytes aligned to 120
+        let mut padded = b"070701".to_vec();
+        for field in [1, libc::S_IFREG | 0o644, 0, 0, 1, 0, 0, 0, 0, 0, 0, 8, 0] {
+            padded.extend_from_slice(format!("{:08x}", field).as_bytes());
+        }
+        padded.extend_from_slice(b"abc\0\0\0\0\0\0\0");
+        padded.extend_from_slice(&Cpio::new().dump());
+        let mut parsed = Cpio::parse(&padded).unwrap();
+        assert!(parsed.exists("abc"));
+        assert_eq!(parsed.dump(), padded);
+        // A new name gets a new size
+        parsed.mv("abc", "abcd").unwrap();
+        assert_eq!(parsed.find("abcd").unwrap().name_size, 0);
+        let reparsed = Cpio::parse(&parsed.dump()).unwrap();
+        assert_eq!(reparsed.find("abcd").unwrap().name_size, 5);
+    }
+
+    #[test]
+    fn test_cpio_edit() {
+        let mut cpio = Cpio::new();
+        cpio.add(0o750, "init", b"init".to_vec());
+        cpio.mkdir(0o755, "overlay.d");
+        cpio.add(0o644, "overlay.d/a.rc", b"on init\n".to_vec());
+
+        cpio.mv("init", "init_back").unwrap();
+        cpio.add(0o750, "init", b"fuseisk".to_vec());
+        assert_eq!(cpio.find("init_back").unwrap().data, b"init");
+        assert_eq!(cpio.find("init").unwrap().data, b"fuseisk");
+        assert_eq!(cpio.ls("/", false).len(), 3);
+        assert_eq!(cpio.ls("overlay.d", false).len(), 1);
+
+        assert!(cpio.rm("overlay.d", false).is_ok());
+        assert!(cpio.exists("overlay.d/a.rc"));
+        cpio.mkdir(0o755, "overlay.d");
+        assert!(cpio.rm("overlay.d", true).is_ok());
+        assert!(!cpio.exists("overlay.d/a.rc"));
+        assert!(cpio.rm("missing", false).is_err());
+
+        // Moving a directory over another one drops the old children
+        cpio.mkdir(0o755, "old");
+        cpio.add(0o644, "old/stale", Vec::new());
+        cpio.mkdir(0o755, "new");
+        cpio.add(0o644, "new/fresh", Vec::new());
+        cpio.mv("new", "old").unwrap();
+        let names: Vec<&str> = cpio.ls("old", true).iter().map(|e| e.path()).collect();
+        assert_eq!(names, ["old/fresh"]);
+        assert!(!cpio.exists("new"));
+        cpio.rm("old", true).unwrap();
+
+        let parsed = Cpio::parse(&cpio.dump()).unwrap();
+        assert_eq!(parsed.entries().len(), 2);
+        assert_eq!(parsed.find("init").unwrap().mode, libc::S_IFREG | 0o750);
+    }
+
+    #[test]
+    fn test_cpio_extract() {
+        let mut cpio = Cpio::new();
+        cpio.mkdir(0o755, "sbin");
+        cpio.add(0o750, "sbin/fuseisk", b"fuseisk".to_vec());
+        cpio.ln("sbin/fuseisk", "init");
+
+        let dir = std::env::temp_dir().join(format!("cpio-test-{}", std::process::id()));
+        cpio.extract_all(&dir).unwrap();
+        assert_eq!(fs::read(dir.join("sbin/fuseisk")).unwrap(), b"fuseisk");
+        assert_eq!(
+            fs::read_link(dir.join("init")).unwrap(),
+            Path::new("sbin/fuseisk")
+        );
+        cpio.extract("sbin/fuseisk", &dir.join("single")).unwrap();
+        assert_eq!(fs::read(dir.join("single")).unwrap(), b"fuseisk");
+        fs::remove_dir_all(&dir).ok();
+
+        let mut evil = Cpio::new();
+        evil.add(0o644, "../escape", Vec::new());
+        assert!(evil.extract_all(&dir).is_err());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    // 写入 boot image 头部的小端字段
+    fn put_u32_at(buf: &mut [u8], off: usize, val: u32) {
+        buf[off..off + 4].copy_from_slice(&val.to_le_bytes());
+    }
+
+    fn pad_to(buf: &mut Vec<u8>, page: usize) {
+        buf.resize(buf.len().div_ceil(page) * page, 0);
+    }
+
+    // mkbootimg 写入 v0-v2 头部的 id：依次为每个 section 及其长度的 SHA1
+    fn boot_id(sections: &[&[u8]]) -> Vec<u8> {
+        let mut sha = Sha1::new();
+        for body in sections {
+            sha.update(body);
+            sha.update((body.len() as u32).to_le_bytes());
+        }
+        sha.finalize().to_vec()
+    }
+
+    #[test]
+    fn test_bootimg_v2() {
+        let page = 2048;
+        let mut img = vec![0_u8; page];
+        img[..8].copy_from_slice(b"ANDROID!");
+        put_u32_at(&mut img, 8, 5);
+        put_u32_at(&mut img, 16, 3);
+        // page_size, header_version
+        put_u32_at(&mut img, 36, page as u32);
+        put_u32_at(&mut img, 40, 2);
+        // Android 11.0.0, 2021-05
+        put_u32_at(&mut img, 44, (11 << 25) | (21 << 4) | 5);
+        put_u32_at(&mut img, 1648, 4);
+        put_u32_at(&mut img, 1644, 1660);
+        img[64..75].copy_from_slice(b"console=tty");
+        img[608..614].copy_from_slice(b"S0 foo");
+        let mut sections = [b"KERNL".to_vec(), b"RD!".to_vec(), b"DTB!".to_vec()];
+        // kernel, ramdisk, empty second, empty recovery_dtbo, dtb
+        let id = boot_id(&[&sections[0], &sections[1], &[], &[], &sections[2]]);
+        img[576..596].copy_from_slice(&id);
+        for body in &sections {
+            img.extend_from_slice(body);
+            pad_to(&mut img, page);
+        }
+        img.extend_from_slice(b"AVB0");
+
+        let mut boot = BootImage::parse(&img).unwrap();
+        assert_eq!(boot.header_version(), 2);
+        assert_eq!(boot.kernel(), b"KERNL");
+        assert_eq!(boot.ramdisk(), b"RD!");
+        assert_eq!(boot.dtb(), b"DTB!");
+        assert_eq!(boot.cmdline(), "console=ttyS0 foo");
+        assert_eq!(boot.os_version().unwrap().to_string(), "11.0.0 (2021-05)");
+        assert_eq!(boot.dump(), img);
+
+        sections[1] = vec![7; page + 1];
+        boot.set_ramdisk(sections[1].clone());
+        let repacked = BootImage::parse(&boot.dump()).unwrap();
+        assert_eq!(repacked.ramdisk(), sections[1].as_slice());
+        assert_eq!(repacked.dtb(), b"DTB!");
+        assert_eq!(repacked.dump().len(), img.len() + page);
+        let id = boot_id(&[&sections[0], &sections[1], &[], &[], &sections[2]]);
+        assert_eq!(repacked.get(Section::Header).unwrap()[576..596], id);
+
+        // The header doesn't fit in the page
+        for bad in [0, 4, 1024, 3000] {
+            put_u32_at(&mut img, 36, bad);
+            assert!(matches!(
+                BootImage::parse(&img),
+                Err(BootImgError::BadPageSize(size)) if size == bad as usize
+            ));
+        }
+    }
+
+    #[test]
+    fn test_bootimg_init_boot_v4() {
+        let page = 4096;
+        let mut img = vec![0_u8; page];
+        img[..8].copy_from_slice(b"ANDROID!");
+        put_u32_at(&mut img, 12, 6);
+        put_u32_at(&mut img, 20, 1584);
+        put_u32_at(&mut img, 40, 4);
+        img.extend_from_slice(b"070701");
+        img.resize(2 * page, 0);
+
+        let mut boot = BootImage::parse(&img).unwrap();
+        assert!(boot.kernel().is_empty());
+        assert_eq!(boot.ramdisk(), b"070701");
+        assert_eq!(boot.dump(), img);
+        boot.set_ramdisk(b"new".to_vec());
+        assert_eq!(BootImage::parse(&boot.dump()).unwrap().ramdisk(), b"new");
+    }
+
+    #[test]
+    fn test_vendor_boot_v4() {
+        let page = 4096;
+        let mut img = vec![0_u8; page];
+        img[..8].copy_from_slice(b"VNDRBOOT");
+        put_u32_at(&mut img, 8, 4);
+        put_u32_at(&mut img, 12, page as u32);
+        put_u32_at(&mut img, 24, 7);
+        put_u32_at(&mut img, 2096, 2128);
+        put_u32_at(&mut img, 2112, 2 * 108);
+        put_u32_at(&mut img, 2116, 2);
+        put_u32_at(&mut img, 2120, 108);
+        put_u32_at(&mut img, 2124, 11);
+        img.extend_from_slice(b"AAABBBB");
+        img.resize(2 * page, 0);
+        let mut table = vec![0_u8; 2 * 108];
+        put_u32_at(&mut table, 0, 3);
+        table[12..16].copy_from_slice(b"init");
+        put_u32_at(&mut table, 108, 4);
+        put_u32_at(&mut table, 108 + 4, 3);
+        table[108 + 12..108 + 16].copy_from_slice(b"dlkm");
+        img.extend_from_slice(&table);
+        img.resize(3 * page, 0);
+        img.extend_from_slice(b"key = value");
+        img.resize(4 * page, 0);
+
+        let mut boot = BootImage::parse(&img).unwrap();
+        assert!(boot.is_vendor_boot());
+        assert_eq!(boot.get(Section::Bootconfig).unwrap(), b"key = value");
+        assert_eq!(boot.dump(), img);
+
+        boot.set_vendor_ramdisk("init", b"CCCCC".to_vec()).unwrap();
+        let entries = boot.vendor_ramdisks();
+        assert_eq!(boot.ramdisk(), b"CCCCCBBBB");
+        assert_eq!((entries[1].offset, entries[1].size), (5, 4));
+        assert!(boot.set_vendor_ramdisk("missing", Vec::new()).is_err());
+
+        // An entry pointing past the end of the ramdisk section
+        let mut bad = img.clone();
+        put_u32_at(&mut bad, 2 * page + 108 + 4, u32::MAX);
+        let mut boot = BootImage::parse(&bad).unwrap();
+        assert!(matches!(
+            boot.set_vendor_ramdisk("dlkm", Vec::new()),
+            Err(BootImgError::BadVendorRamdisk(_))
+        ));
+    }
+
+    #[test]
+    fn test_compress_round_trip() {
+        let data: Vec<u8> = b"070701 fuseisk ramdisk "
+            .iter()
+            .cycle()
+            .take(64 * 1024)
+            .copied()
+            .collect();
+        for format in [
+            Format::Gzip,
+            Format::Xz,
+            Format::Lzma,
+            Format::Lz4,
+            Format::Lz4Legacy,
+            Format::Zstd,
+        ] {
+            let packed = compress(format, &data).unwrap();
+            assert_eq!(Format::detect(&packed), format, "{}", format);
+            // Repacked ramdisks have to fit back into the partition
+            assert!(packed.len() < data.len() / 16, "{}", format);
+            let (detected, unpacked) = decompress(&packed).unwrap();
+            assert_eq!(detected, format);
+            assert_eq!(unpacked, data, "{}", format);
+            assert_eq!(Format::from_name(format.name()), Some(format));
+        }
+        assert_eq!(Format::detect(&data), Format::Unknown);
+        assert!(decompress(&data).is_err());
+    }
+
+    #[test]
+    fn test_lz4_legacy_size_trailer() {
+        let data = vec![0x5a_u8; 1000];
+        let mut packed = compress(Format::Lz4Legacy, &data).unwrap();
+        packed.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        assert_eq!(decompress_as(Format::Lz4Legacy, &packed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_mountinfo_parse() {
+        let table = MountTable::parse(
+            "\
+22 1 0:20 / / rw,relatime - rootfs rootfs rw
+23 22 0:4 / /proc rw,relatime shared:2 master:1 - proc proc rw
+24 22 0:21 / /data rw,nosuid - tmpfs tmpfs rw,mode=755
+25 24 259:3 /fuseisk /data/preinit rw,relatime - ext4 /dev/fuseisk/block/metadata rw
+26 22 259:3 / /mnt/with\\040space ro - ext4 /dev/fuseisk/block/metadata rw
+27 24 0:22 / /data rw - tmpfs none rw
+garbage
+",
+        );
+        assert_eq!(table.mounts().len(), 6);
+
+        let proc = table.find("/proc").unwrap();
+        assert_eq!(
+            (proc.id, proc.parent, proc.major, proc.minor),
+            (23, 22, 0, 4)
+        );
+        assert_eq!(proc.optional, ["shared:2", "master:1"]);
+        assert_eq!(proc.fs_type, "proc");
+        assert_eq!(proc.super_options, "rw");
+
+        let preinit = table.find("/data/preinit").unwrap();
+        assert_eq!(preinit.root, "/fuseisk");
+        assert_eq!(preinit.source, "/dev/fuseisk/block/metadata");
+        assert!(table.find("/mnt/with space").unwrap().is_read_only());
+
+        // The last mount on a target is the visible one
+        assert_eq!(table.find("/data").unwrap().id, 27);
+        assert!(table.is_mounted("/data"));
+        assert!(!table.is_mounted("/data/"));
+        assert!(!table.is_mounted("/dat"));
+
+        let under: Vec<u32> = table.mounts_under("/data").iter().map(|m| m.id).collect();
+        assert_eq!(under, [24, 25, 27]);
+        assert_eq!(table.mounts_under("/").len(), 6);
+        assert_eq!(table.find_by_source("/dev/fuseisk/block/metadata").len(), 2);
+        assert_eq!(table.find_by_dev(259, 3)[0].target, "/data/preinit");
+    }
+
+    #[test]
+    fn test_mount_stack() {
+        // Nothing is mounted at these, only the bookkeeping runs
+        let mut stack = MountStack::default();
+        stack.push("/fuseisk-test/proc");
+        stack.push_kept("/fuseisk-test/data");
+
+        let guard = stack.guard();
+        stack.push_kept("/fuseisk-test/sdcard");
+        stack.push("/fuseisk-test/dev");
+        assert_eq!(stack.len(), 4);
+        stack.rollback(guard);
+        assert_eq!(stack.len(), 2);
+
+        let guard = stack.guard();
+        stack.push_kept("/fuseisk-test/init");
+        guard.commit();
+        stack.unmount_temporary();
+        assert_eq!(stack.mounts(), ["/fuseisk-test/data", "/fuseisk-test/init"]);
+    }
+
+    // FUSE 协议常量，见 include/uapi/linux/fuse.h
+    const FUSE_LOOKUP: u32 = 1;
+    const FUSE_FORGET: u32 = 2;
+    const FUSE_GETATTR: u32 = 3;
+    const FUSE_READLINK: u32 = 5;
+    const FUSE_OPEN: u32 = 14;
+    const FUSE_READ: u32 = 15;
+    const FUSE_STATFS: u32 = 17;
+    const FUSE_INIT: u32 = 26;
+    const FUSE_READDIR: u32 = 28;
+    const FUSE_BATCH_FORGET: u32 = 42;
+    const FUSE_READDIRPLUS: u32 = 44;
+    const FUSE_ASYNC_READ: u32 = 1 << 0;
+    const FUSE_DO_READDIRPLUS: u32 = 1 << 13;
+    const IN_HEADER_SIZE: usize = 40;
+    const OUT_HEADER_SIZE: usize = 16;
+    const ENTRY_OUT_SIZE: usize = 128;
+
+    fn push_u32(buf: &mut Vec<u8>, val: u32) {
+        buf.extend_from_slice(&val.to_ne_bytes());
+    }
+
+    fn push_u64(buf: &mut Vec<u8>, val: u64) {
+        buf.extend_from_slice(&val.to_ne_bytes());
+    }
+
+    fn fuse_request(opcode: u32, nodeid: u64, args: &[u8]) -> Vec<u8> {
+        let mut req = Vec::new();
+        push_u32(&mut req, (IN_HEADER_SIZE + args.len()) as u32);
+        push_u32(&mut req, opcode);
+        // unique
+        push_u64(&mut req, 42);
+        push_u64(&mut req, nodeid);
+        req.resize(IN_HEADER_SIZE, 0);
+        req.extend_from_slice(args);
+        req
+    }
+
+    fn fuse_u32_at(buf: &[u8], off: usize) -> u32 {
+        u32::from_ne_bytes(buf[off..off + 4].try_into().unwrap())
+    }
+
+    fn fuse_u64_at(buf: &[u8], off: usize) -> u64 {
+        u64::from_ne_bytes(buf[off..off + 8].try_into().unwrap())
+    }
+
+    fn fuse_error(reply: &[u8]) -> i32 {
+        fuse_u32_at(reply, 4) as i32
+    }
+
+    fn fuse_init_args(major: u32, minor: u32) -> Vec<u8> {
+        let mut init = Vec::new();
+        push_u32(&mut init, major);
+        push_u32(&mut init, minor);
+        // max_readahead
+        push_u32(&mut init, 0x20000);
+        push_u32(&mut init, FUSE_ASYNC_READ | FUSE_DO_READDIRPLUS | (1 << 1));
+        init
+    }
+
+    // 根目录下只有一个文件 "hello"，lookups 记录内核持有的引用数
+    #[derive(Default)]
+    struct TestFs {
+        lookups: Rc<Cell<u64>>,
+    }
+
+    impl Filesystem for TestFs {
+        fn lookup(&mut self, parent: u64, name: &OsStr) -> io::Result<Attr> {
+            match (parent, name.to_str()) {
+                (FUSE_ROOT_ID, Some("hello")) => {
+                    self.lookups.set(self.lookups.get() + 1);
+                    self.getattr(2)
+                }
+                _ => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+            }
+        }
+
+        fn forget(&mut self, _ino: u64, nlookup: u64) {
+            self.lookups.set(self.lookups.get() - nlookup);
+        }
+
+        fn getattr(&mut self, ino: u64) -> io::Result<Attr> {
+            match ino {
+                FUSE_ROOT_ID => Ok(Attr {
+                    ino,
+                    mode: libc::S_IFDIR | 0o755,
+                    ..Attr::default()
+                }),
+                2 => Ok(Attr {
+                    ino,
+                    size: 5,
+                    mode: libc::S_IFREG | 0o644,
+                    ..Attr::default()
+                }),
+                _ => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+            }
+        }
+
+        fn readlink(&mut self, _ino: u64) -> io::Result<Vec<u8>> {
+            Err(io::Error::from_raw_os_error(libc::EINVAL))
+        }
+
+        fn open(&mut self, _ino: u64, _flags: i32) -> io::Result<u64> {
+            Ok(7)
+        }
+
+        fn read(&mut self, _ino: u64, _fh: u64, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+            Ok(b"world"
+                .iter()
+                .skip(offset as usize)
+                .take(size as usize)
+                .copied()
+                .collect())
+        }
+
+        fn release(&mut self, _ino: u64, _fh: u64) {}
+
+        fn readdir(&mut self, ino: u64) -> io::Result<Vec<DirEntry>> {
+            let entry = |ino, kind, name: &str| DirEntry {
+                ino,
+                kind,
+                name: OsString::from(name),
+            };
+            Ok(vec![
+                entry(ino, libc::S_IFDIR, "."),
+                entry(ino, libc::S_IFDIR, ".."),
+                entry(2, libc::S_IFREG, "hello"),
+            ])
+        }
+    }
+
+    // 已完成 7.38 INIT 的 session
+    fn fuse_session() -> (Session<TestFs>, Rc<Cell<u64>>) {
+        let fs = TestFs::default();
+        let lookups = fs.lookups.clone();
+        let mut session = Session::new(fs);
+        let reply = session
+            .handle(&fuse_request(FUSE_INIT, 0, &fuse_init_args(7, 38)))
+            .unwrap();
+        assert_eq!(fuse_error(&reply), 0);
+        (session, lookups)
+    }
+
+    #[test]
+    fn test_fuse_before_init() {
+        let mut session = Session::new(TestFs::default());
+        let reply = session
+            .handle(&fuse_request(FUSE_GETATTR, FUSE_ROOT_ID, &[0; 16]))
+            .unwrap();
+        assert_eq!(fuse_error(&reply), -libc::EIO);
+        assert_eq!(fuse_u64_at(&reply, 8), 42);
+    }
+
+    #[test]
+    fn test_fuse_init() {
+        let mut session = Session::new(TestFs::default());
+        let reply = session
+            .handle(&fuse_request(FUSE_INIT, 0, &fuse_init_args(7, 38)))
+            .unwrap();
+        assert_eq!(fuse_error(&reply), 0);
+        assert_eq!(reply.len(), OUT_HEADER_SIZE + 64);
+        assert_eq!(fuse_u32_at(&reply, 0) as usize, reply.len());
+        assert_eq!((fuse_u32_at(&reply, 16), fuse_u32_at(&reply, 20)), (7, 31));
+        assert_eq!(fuse_u32_at(&reply, 24), 0x20000);
+        assert_eq!(
+            fuse_u32_at(&reply, 28),
+            FUSE_ASYNC_READ | FUSE_DO_READDIRPLUS
+        );
+    }
+
+    #[test]
+    fn test_fuse_init_old_kernel() {
+        // 7.23 之前的内核使用较短的 fuse_init_out
+        let mut session = Session::new(TestFs::default());
+        let reply = session
+            .handle(&fuse_request(FUSE_INIT, 0, &fuse_init_args(7, 22)))
+            .unwrap();
+        assert_eq!((reply.len(), fuse_error(&reply)), (OUT_HEADER_SIZE + 24, 0));
+        assert_eq!(fuse_u32_at(&reply, 20), 22);
+
+        let mut session = Session::new(TestFs::default());
+        let reply = session
+            .handle(&fuse_request(FUSE_INIT, 0, &fuse_init_args(7, 8)))
+            .unwrap();
+        assert_eq!(fuse_error(&reply), -libc::EPROTO);
+        let reply = session
+            .handle(&fuse_request(FUSE_INIT, 0, &fuse_init_args(6, 38)))
+            .unwrap();
+        assert_eq!(fuse_error(&reply), -libc::EPROTO);
+    }
+
+    #[test]
+    fn test_fuse_init_newer_major() {
+        // 与 libfuse 相同：回复我们的版本，内核会再发一次 INIT
+        let mut session = Session::new(TestFs::default());
+        let reply = session
+            .handle(&fuse_request(FUSE_INIT, 0, &fuse_init_args(8, 0)))
+            .unwrap();
+        assert_eq!((reply.len(), fuse_error(&reply)), (OUT_HEADER_SIZE + 64, 0));
+        assert_eq!((fuse_u32_at(&reply, 16), fuse_u32_at(&reply, 20)), (7, 31));
+        let reply = session
+            .handle(&fuse_request(FUSE_GETATTR, FUSE_ROOT_ID, &[0; 16]))
+            .unwrap();
+        assert_eq!(fuse_error(&reply), -libc::EIO);
+
+        let reply = session
+            .handle(&fuse_request(FUSE_INIT, 0, &fuse_init_args(7, 31)))
+            .unwrap();
+        assert_eq!(fuse_error(&reply), 0);
+    }
+
+    #[test]
+    fn test_fuse_lookup() {
+        let (mut session, lookups) = fuse_session();
+        let reply = session
+            .handle(&fuse_request(FUSE_LOOKUP, FUSE_ROOT_ID, b"hello\0"))
+            .unwrap();
+        assert_eq!(reply.len(), OUT_HEADER_SIZE + ENTRY_OUT_SIZE);
+        // nodeid, then the size in fuse_attr
+        assert_eq!(fuse_u64_at(&reply, 16), 2);
+        assert_eq!(fuse_u64_at(&reply, 16 + 48), 5);
+        assert_eq!(lookups.get(), 1);
+
+        let reply = session
+            .handle(&fuse_request(FUSE_LOOKUP, FUSE_ROOT_ID, b"nope\0"))
+            .unwrap();
+        assert_eq!(
+            (reply.len(), fuse_error(&reply)),
+            (OUT_HEADER_SIZE, -libc::ENOENT)
+        );
+        // A name without its NUL
+        let reply = session
+            .handle(&fuse_request(FUSE_LOOKUP, FUSE_ROOT_ID, b"hello"))
+            .unwrap();
+        assert_eq!(fuse_error(&reply), -libc::EIO);
+    }
+
+    #[test]
+    fn test_fuse_getattr() {
+        let (mut session, _) = fuse_session();
+        let reply = session
+            .handle(&fuse_request(FUSE_GETATTR, 2, &[0; 16]))
+            .unwrap();
+        assert_eq!(reply.len(), OUT_HEADER_SIZE + 104);
+        assert_eq!(fuse_u32_at(&reply, 16 + 16 + 60), libc::S_IFREG | 0o644);
+    }
+
+    #[test]
+    fn test_fuse_open_read() {
+        let (mut session, _) = fuse_session();
+        let reply = session
+            .handle(&fuse_request(FUSE_OPEN, 2, &[0; 8]))
+            .unwrap();
+        assert_eq!(fuse_u64_at(&reply, 16), 7);
+        let mut read = Vec::new();
+        push_u64(&mut read, 7);
+        push_u64(&mut read, 1);
+        push_u32(&mut read, 3);
+        let reply = session.handle(&fuse_request(FUSE_READ, 2, &read)).unwrap();
+        assert_eq!(&reply[OUT_HEADER_SIZE..], b"orl");
+    }
+
+    #[test]
+    fn test_fuse_readdirplus() {
+        let (mut session, lookups) = fuse_session();
+        // Room for "." and ".." only, "hello" comes with the next call
+        let mut readdir = Vec::new();
+        push_u64(&mut readdir, 0);
+        push_u64(&mut readdir, 0);
+        push_u32(&mut readdir, 2 * (ENTRY_OUT_SIZE + 32) as u32 + 8);
+        let reply = session
+            .handle(&fuse_request(FUSE_READDIRPLUS, FUSE_ROOT_ID, &readdir))
+            .unwrap();
+        assert_eq!(reply.len(), OUT_HEADER_SIZE + 2 * (ENTRY_OUT_SIZE + 32));
+        // "." has no nodeid, the kernel does not look it up
+        assert_eq!(fuse_u64_at(&reply, 16), 0);
+        assert_eq!(lookups.get(), 0);
+
+        readdir[8..16].copy_from_slice(&2u64.to_ne_bytes());
+        readdir[16..20].copy_from_slice(&4096u32.to_ne_bytes());
+        let reply = session
+            .handle(&fuse_request(FUSE_READDIRPLUS, FUSE_ROOT_ID, &readdir))
+            .unwrap();
+        assert_eq!(reply.len(), OUT_HEADER_SIZE + ENTRY_OUT_SIZE + 32);
+        let dirent = OUT_HEADER_SIZE + ENTRY_OUT_SIZE;
+        assert_eq!(fuse_u64_at(&reply, 16), 2);
+        assert_eq!(fuse_u64_at(&reply, dirent + 8), 3);
+        assert_eq!(fuse_u32_at(&reply, dirent + 20), libc::DT_REG as u32);
+        assert_eq!(&reply[dirent + 24..dirent + 29], b"hello");
+        assert_eq!(lookups.get(), 1);
+    }
+
+    #[test]
+    fn test_fuse_readdir() {
+        let (mut session, lookups) = fuse_session();
+        let mut readdir = Vec::new();
+        push_u64(&mut readdir, 0);
+        push_u64(&mut readdir, 2);
+        push_u32(&mut readdir, 4096);
+        let reply = session
+            .handle(&fuse_request(FUSE_READDIR, FUSE_ROOT_ID, &readdir))
+            .unwrap();
+        assert_eq!(reply.len(), OUT_HEADER_SIZE + 32);
+        // Plain readdir does not look anything up
+        assert_eq!(lookups.get(), 0);
+    }
+
+    #[test]
+    fn test_fuse_forget() {
+        let (mut session, lookups) = fuse_session();
+        for _ in 0..2 {
+            session
+                .handle(&fuse_request(FUSE_LOOKUP, FUSE_ROOT_ID, b"hello\0"))
+                .unwrap();
+        }
+        assert_eq!(lookups.get(), 2);
+
+        // Forgetting has no reply
+        let mut forget = Vec::new();
+        push_u32(&mut forget, 1);
+        push_u32(&mut forget, 0);
+        push_u64(&mut forget, 2);
+        push_u64(&mut forget, 1);
+        assert!(session
+            .handle(&fuse_request(FUSE_BATCH_FORGET, 0, &forget))
+            .is_none());
+        assert!(session
+            .handle(&fuse_request(FUSE_FORGET, 2, &1u64.to_ne_bytes()))
+            .is_none());
+        assert_eq!(lookups.get(), 0);
+    }
+
+    #[test]
+    fn test_fuse_errors() {
+        let (mut session, _) = fuse_session();
+        let reply = session
+            .handle(&fuse_request(FUSE_READLINK, 2, &[]))
+            .unwrap();
+        assert_eq!(fuse_error(&reply), -libc::EINVAL);
+        let reply = session.handle(&fuse_request(FUSE_STATFS, 2, &[])).unwrap();
+        assert_eq!(fuse_error(&reply), -libc::ENOSYS);
+    }
+
+    #[test]
+    fn test_fuse_malformed() {
+        let (mut session, _) = fuse_session();
+        // A truncated request is still answered
+        let reply = session
+            .handle(&fuse_request(FUSE_LOOKUP, 1, b"x\0")[..30])
+            .unwrap();
+        assert_eq!(
+            (fuse_error(&reply), fuse_u64_at(&reply, 8)),
+            (-libc::EIO, 42)
+        );
+        let mut request = fuse_request(FUSE_LOOKUP, 1, b"x\0");
+        request[0..4].copy_from_slice(&100u32.to_ne_bytes());
+        let reply = session.handle(&request).unwrap();
+        assert_eq!(
+            (reply.len(), fuse_error(&reply)),
+            (OUT_HEADER_SIZE, -libc::EIO)
+        );
+        // Too short to tell which request it was
+        assert!(session.handle(&request[..12]).is_none());
     }
 }